@@ -0,0 +1,79 @@
+// Measures syntax highlighting on a 50k-line file: the first pass, and the work left after an edit
+// once the line-state cache is warm. Run with `cargo bench --bench highlight`.
+
+use vce::editor::{Cell,Document};
+use vce::lexer::SyntaxHighlighter;
+
+use std::time::{Duration,Instant};
+
+const LINES: usize = 50_000;
+const SCREEN: usize = 40;
+const EDITS: u32 = 200;
+
+const CONFIG: &str = r#"color rgb(197,134,192) (?P<color>\b(fn|let|mut|if|else|return|for|in|while)\b)
+color rgb(181,206,168) (?P<color>\b[0-9]+\b)
+color rgb(206,145,120) (?P<color>"[^"]*")
+color rgb(106,153,85) (?P<color>//.*$)
+push comment /\*
+context comment rgb(106,153,85)
+    push comment /\*
+    pop \*/
+end
+file rs
+"#;
+
+fn sample_text() -> String {
+    let mut text = String::new();
+    while text.lines().count() < LINES {
+        let i = text.len();
+        text.push_str(&format!("/* Adds {} to its argument.\n   Nothing more to it. */\nfn add_{}(x: usize) -> usize {{\n    let label = \"add\"; // the name\n    x + {}\n}}\n\n",i,i,i));
+    }
+    text
+}
+
+fn main() {
+    let mut highlighter = SyntaxHighlighter::new();
+    highlighter.load("bench", CONFIG);
+    let mut doc = Document::new("bench.rs".to_string(), "bench.rs".to_string());
+    doc.file_type = Some("rs".to_string());
+    doc.insert_str(&sample_text());
+    let lines = doc.line_count();
+
+    let start = Instant::now();
+    highlighter.update(&doc.text, &doc.file_type, &mut doc.highlights, lines);
+    println!("first pass over {} lines: {:?}",lines,start.elapsed());
+
+    // Typing in the middle of the file, with the screen showing the lines around the cursor.
+    let line = lines / 2;
+    doc.cursor_pos = doc.pos_at(line + 3, 8);
+    let mut typing = Duration::new(0, 0);
+    for _ in 0..EDITS {
+        doc.insert(Cell::Char('a'));
+        let start = Instant::now();
+        highlighter.update(&doc.text, &doc.file_type, &mut doc.highlights, line + SCREEN);
+        typing += start.elapsed();
+    }
+    println!("typing a character: {:?} per keystroke",typing / EDITS);
+
+    // Opening a block comment outside any other changes the state of every line after it, since the
+    // comments further down nest inside it, until it's closed again.
+    let comment_line = line + 5;
+    assert!(doc.line_text(comment_line).starts_with("fn add_"));
+    doc.cursor_pos = doc.pos_at(comment_line, 0);
+    let start = Instant::now();
+    doc.insert_str("/*");
+    let opened = highlighter.update(&doc.text, &doc.file_type, &mut doc.highlights, lines);
+    let opening = start.elapsed();
+    assert_eq!(opened, lines - comment_line);
+
+    let start = Instant::now();
+    doc.insert_str("*/");
+    let closed = highlighter.update(&doc.text, &doc.file_type, &mut doc.highlights, lines);
+    let closing = start.elapsed();
+    println!("opening a block comment: {:?} for {} lines, closing it again: {:?} for {} lines",opening,opened,closing,closed);
+
+    doc.highlights.clear();
+    let start = Instant::now();
+    highlighter.update(&doc.text, &doc.file_type, &mut doc.highlights, line + SCREEN);
+    println!("without the cache, the same screen takes: {:?}",start.elapsed());
+}
@@ -0,0 +1,68 @@
+// Measures how much the CharGrid backend sends to the terminal per frame.
+// Run with `cargo bench --bench render`.
+
+use vce::editor::Editor;
+use vce::windowing::Drawable;
+use vce::windowing::backends::CharGrid;
+
+use crossterm::event::{Event,KeyEvent,KeyCode,KeyModifiers};
+use std::time::Instant;
+
+const WIDTH: usize = 120;
+const HEIGHT: usize = 40;
+const KEYSTROKES: usize = 1000;
+
+fn sample_text() -> String {
+    let mut text = String::new();
+    for i in 0..500 {
+        text.push_str(&format!("fn function_{}(x: usize) -> usize {{\n    let y = x * {} + 1; // some comment\n    y\n}}\n\n",i,i));
+    }
+    text
+}
+
+fn frame(editor: &mut Editor,screen: &mut CharGrid) -> usize {
+    screen.clear(' ');
+    editor.draw(screen);
+    let mut out = Vec::new();
+    screen.render(&mut out).unwrap();
+    out.len()
+}
+
+fn key(editor: &mut Editor,screen: &mut CharGrid,code: KeyCode) {
+    editor.update(screen, Event::Key(KeyEvent::new(code, KeyModifiers::NONE)));
+}
+
+fn main() {
+    let mut editor = Editor::new();
+    // Any complaint about the config files found on this machine shouldn't be drawn.
+    editor.message = None;
+    editor.make_new_doc("bench.rs".to_string());
+    let doc = &mut editor.open_docs[0];
+    doc.insert_str(&sample_text());
+    doc.cursor_pos = doc.pos_at(20, 8);
+
+    let mut screen = CharGrid::new(WIDTH, HEIGHT);
+    let first = frame(&mut editor, &mut screen);
+    println!("first frame ({}x{}): {} bytes",WIDTH,HEIGHT,first);
+
+    let mut typed = 0;
+    let start = Instant::now();
+    for i in 0..KEYSTROKES {
+        key(&mut editor, &mut screen, KeyCode::Char((b'a' + (i % 26) as u8) as char));
+        typed += frame(&mut editor, &mut screen);
+        if i % 40 == 39 {
+            key(&mut editor, &mut screen, KeyCode::Enter);
+            typed += frame(&mut editor, &mut screen);
+        }
+    }
+    let elapsed = start.elapsed();
+    let frames = KEYSTROKES + KEYSTROKES / 40;
+    println!("typing: {} bytes/frame on average, {:?}/frame",typed / frames,elapsed / frames as u32);
+
+    let mut moved = 0;
+    for _ in 0..100 {
+        key(&mut editor, &mut screen, KeyCode::Down);
+        moved += frame(&mut editor, &mut screen);
+    }
+    println!("cursor down: {} bytes/frame on average",moved / 100);
+}
@@ -0,0 +1,48 @@
+use std::io::{Write,stdout};
+
+const BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Default)]
+pub struct Clipboard {
+    pub register: String,
+    // Also send copies to the host terminal's clipboard with an OSC 52 escape, which works over SSH.
+    pub osc52: bool
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn copy(&mut self,text: String) {
+        if self.osc52 {
+            print!("\x1b]52;c;{}\x07",base64(text.as_bytes()));
+            let _ = stdout().flush();
+        }
+        self.register = text;
+    }
+
+    pub fn paste(&self) -> &str {
+        &self.register
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            }
+            else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
@@ -0,0 +1,137 @@
+use crossterm::style::Color;
+
+// How many colors the terminal can show. Styles are written in RGB and brought down to this when drawn.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ColorMode {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+    // No colors at all, for NO_COLOR or terminals that can't show any.
+    Off
+}
+
+// xterm's values for the 16 ANSI colors, in palette order.
+const ANSI_16: [(u8,u8,u8); 16] = [
+    (0,0,0), (205,0,0), (0,205,0), (205,205,0), (0,0,238), (205,0,205), (0,205,205), (229,229,229),
+    (127,127,127), (255,0,0), (0,255,0), (255,255,0), (92,92,255), (255,0,255), (0,255,255), (255,255,255)
+];
+
+// The steps of each channel in the 6x6x6 color cube of the 256-color palette.
+const CUBE_LEVELS: [u8; 6] = [0,95,135,175,215,255];
+
+impl ColorMode {
+    // Works out what the terminal supports from the environment, following the usual conventions:
+    // NO_COLOR turns colors off, COLORTERM announces 24-bit support and TERM names 256-color terminals.
+    pub fn detect() -> Self {
+        let var = |name: &str| std::env::var(name).unwrap_or_default();
+
+        if !var("NO_COLOR").is_empty() {
+            return ColorMode::Off;
+        }
+
+        let colorterm = var("COLORTERM").to_ascii_lowercase();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            return ColorMode::TrueColor;
+        }
+
+        let term = var("TERM").to_ascii_lowercase();
+        if term == "dumb" {
+            ColorMode::Off
+        }
+        else if term.contains("truecolor") || term.contains("direct") {
+            ColorMode::TrueColor
+        }
+        else if term.contains("256color") {
+            ColorMode::Ansi256
+        }
+        else {
+            ColorMode::Ansi16
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "truecolor" | "24bit" => Some(ColorMode::TrueColor),
+            "256" => Some(ColorMode::Ansi256),
+            "16" => Some(ColorMode::Ansi16),
+            "off" | "none" => Some(ColorMode::Off),
+            _ => None
+        }
+    }
+
+    // The closest color the terminal can show, or None if it shouldn't get a color at all.
+    pub fn quantize(self,color: Color) -> Option<Color> {
+        match (self,color) {
+            (ColorMode::Off,_) => None,
+            (ColorMode::TrueColor,_) => Some(color),
+            (ColorMode::Ansi256,Color::Rgb { r, g, b }) => Some(Color::AnsiValue(nearest_256((r,g,b)))),
+            (ColorMode::Ansi16,Color::Rgb { r, g, b }) => Some(ansi_16(nearest_16((r,g,b)))),
+            (ColorMode::Ansi16,Color::AnsiValue(value)) if value >= 16 => Some(ansi_16(nearest_16(palette_rgb(value)))),
+            (ColorMode::Ansi16,Color::AnsiValue(value)) => Some(ansi_16(value)),
+            _ => Some(color)
+        }
+    }
+}
+
+fn distance(a: (u8,u8,u8),b: (u8,u8,u8)) -> u32 {
+    let d = |x: u8,y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+fn nearest_16(rgb: (u8,u8,u8)) -> u8 {
+    (0..16).min_by_key(|&i| distance(rgb, ANSI_16[i])).unwrap() as u8
+}
+
+// Picks between the closest color in the cube and the closest shade on the grey ramp. The 16 system
+// colors are left out since terminals often redefine them.
+fn nearest_256(rgb: (u8,u8,u8)) -> u8 {
+    let level = |c: u8| (0..6).min_by_key(|&i| (CUBE_LEVELS[i] as i32 - c as i32).abs()).unwrap();
+    let (r,g,b) = (level(rgb.0),level(rgb.1),level(rgb.2));
+    let cube = (16 + 36 * r + 6 * g + b) as u8;
+
+    let average = (rgb.0 as u32 + rgb.1 as u32 + rgb.2 as u32) / 3;
+    let grey = (232 + (average.saturating_sub(3) / 10).min(23)) as u8;
+
+    if distance(rgb, palette_rgb(grey)) < distance(rgb, palette_rgb(cube)) {
+        grey
+    }
+    else {
+        cube
+    }
+}
+
+// The RGB value of an entry in the xterm 256-color palette.
+fn palette_rgb(value: u8) -> (u8,u8,u8) {
+    match value {
+        0..=15 => ANSI_16[value as usize],
+        16..=231 => {
+            let i = (value - 16) as usize;
+            (CUBE_LEVELS[i / 36],CUBE_LEVELS[i / 6 % 6],CUBE_LEVELS[i % 6])
+        },
+        _ => {
+            let level = 8 + 10 * (value - 232);
+            (level,level,level)
+        }
+    }
+}
+
+fn ansi_16(value: u8) -> Color {
+    match value {
+        0 => Color::Black,
+        1 => Color::DarkRed,
+        2 => Color::DarkGreen,
+        3 => Color::DarkYellow,
+        4 => Color::DarkBlue,
+        5 => Color::DarkMagenta,
+        6 => Color::DarkCyan,
+        7 => Color::Grey,
+        8 => Color::DarkGrey,
+        9 => Color::Red,
+        10 => Color::Green,
+        11 => Color::Yellow,
+        12 => Color::Blue,
+        13 => Color::Magenta,
+        14 => Color::Cyan,
+        _ => Color::White
+    }
+}
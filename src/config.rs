@@ -0,0 +1,48 @@
+use std::path::{Path,PathBuf};
+use std::time::SystemTime;
+
+use crate::error::EditorError;
+
+// The grammars compiled into the binary, so vce highlights something even with no config files around.
+pub const DEFAULT_SYNTAX: &str = include_str!("../Config/syntax.txt");
+pub const DEFAULT_SETTINGS: &str = include_str!("../Config/settings.toml");
+
+// The name of the directory that marks a project's own config, e.g. `my-project/.vce/syntax.txt`.
+const PROJECT_DIR: &str = ".vce";
+
+// The user's config directories, lowest priority first: `Config` next to the executable, where vce has
+// always looked, then `$XDG_CONFIG_HOME/vce` (or `~/.config/vce`).
+pub fn user_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(mut exe) = std::env::current_exe() {
+        exe.pop();
+        dirs.push(exe.join("Config"));
+    }
+    if let Some(config) = std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        dirs.push(PathBuf::from(config).join("vce"));
+    }
+    else if let Some(home) = std::env::var_os("HOME").filter(|dir| !dir.is_empty()) {
+        dirs.push(PathBuf::from(home).join(".config").join("vce"));
+    }
+    dirs
+}
+
+// The nearest `.vce` directory at or above the directory `file` is in.
+pub fn project_dir(file: &Path) -> Option<PathBuf> {
+    let file = std::fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+    file.ancestors().skip(1).map(|dir| dir.join(PROJECT_DIR)).find(|dir| dir.is_dir())
+}
+
+// Reads a config file, treating a missing one as empty.
+pub fn read(path: &Path) -> Result<Option<String>,EditorError> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(Some(text)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(EditorError::io(&path.display().to_string(), err))
+    }
+}
+
+// When a config file was last changed, or None if it doesn't exist.
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
@@ -1,221 +1,640 @@
 use std::io::{Write,stdout};
+use std::path::{Path,PathBuf};
+use std::time::SystemTime;
 
-use crate::windowing::Drawable;
-use crossterm::style::Color;
+use crate::windowing::{Drawable,Style};
 
-use std::io::BufRead;
-use std::io::BufReader;
-use std::fs::File;
+use ropey::Rope;
+use unicode_segmentation::UnicodeSegmentation;
 
 use crossterm::event::Event;
 use crossterm::event::KeyCode;
 use crossterm::event::KeyModifiers;
-
-use crate::lexer::SyntaxHighlighter;
+use crossterm::event::MouseEvent;
+use crossterm::event::MouseButton;
+
+use crate::lexer::{SyntaxHighlighter,HighlightCache,Scope};
+use crate::theme::Theme;
+use crate::color::ColorMode;
+use crate::config;
+use crate::settings::{Settings,SettingsTable,LineNumbers};
+use crate::editorconfig::EditorConfig;
+use crate::history::{History,Edit};
+use crate::clipboard::Clipboard;
+use crate::search::Search;
+use crate::status::{Message,MessageKind};
+use crate::save::{self,Backup};
+use crate::format::{self,FileFormat,LineEnding,Encoding};
+use crate::error::EditorError;
+use crate::unicode;
 use crate::windowing::UpdateResult;
 
 pub struct Editor {
     pub open_docs: Vec<Document>,
     pub currently_open_doc: Option<usize>,
-    pub highlighter: SyntaxHighlighter,
+    pub configs: Configs,
+    pub theme: Theme,
+    // What the terminal can show. Detected from the environment unless the settings set `color_mode`.
+    pub color_mode: ColorMode,
+    // Set by `--colors`, and wins over the settings files.
+    pub forced_color_mode: Option<ColorMode>,
+    // The user's config directories, lowest priority first. A document's project directory is layered over
+    // them for that document only.
+    pub config_dirs: Vec<PathBuf>,
+    // A config file given with `--config`, layered over everything else: settings if it's a `.toml` file,
+    // otherwise syntax.
+    pub config_file: Option<PathBuf>,
+    // Modification times of the config files as of the last load, to notice when they change.
+    config_stamps: Vec<(PathBuf,Option<SystemTime>)>,
     pub start_line: usize,
-    pub tab_str: String,
-    pub deviation: usize
+    pub clipboard: Clipboard,
+    pub search: Search,
+    pub replacement: String,
+    pub prompt: Option<Prompt>,
+    pub prompt_input: String,
+    pub message: Option<Message>,
+    pub backup: Backup
 }
 
-impl Editor {
+// The grammars and settings built from one list of config directories.
+pub struct Configuration {
+    pub highlighter: SyntaxHighlighter,
+    pub settings: SettingsTable
+}
+
+impl Configuration {
     pub fn new() -> Self {
         Self {
+            highlighter: SyntaxHighlighter::new(),
+            settings: SettingsTable::new()
+        }
+    }
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// One configuration for documents outside any project, and one for each project with open documents,
+// which adds the project's `.vce` directory on top.
+pub struct Configs {
+    pub base: Configuration,
+    pub projects: HashMap<PathBuf,Configuration>
+}
+
+impl Configs {
+    pub fn new() -> Self {
+        Self {
+            base: Configuration::new(),
+            projects: HashMap::new()
+        }
+    }
+
+    pub fn for_doc(&self,doc: &Document) -> &Configuration {
+        doc.project.as_ref().and_then(|dir| self.projects.get(dir)).unwrap_or(&self.base)
+    }
+
+    pub fn settings(&self,doc: &Document) -> &Settings {
+        self.for_doc(doc).settings.for_type(&doc.file_type)
+    }
+}
+
+impl Default for Configs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub enum Prompt {
+    // Incremental search, remembering where the cursor was when it started.
+    Find { origin: usize },
+    Replace { origin: usize, stage: ReplaceStage },
+    // Asking whether to save a modified document before quitting.
+    SaveChanges { doc: usize },
+    // Asking for the path to save a document to, and whether we're in the middle of quitting.
+    SaveAs { doc: usize, quitting: bool },
+    ConfirmOverwrite { doc: usize, quitting: bool },
+    // A command line for actions without a key of their own, such as converting line endings.
+    Command
+}
+
+#[derive(Clone,Copy,PartialEq)]
+pub enum ReplaceStage {
+    // Typing the search query.
+    Find,
+    // Typing the replacement.
+    With,
+    // Stepping through matches one at a time, counting replacements made so far.
+    Confirm { replaced: usize }
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        let mut editor = Self {
             open_docs: Vec::new(),
             currently_open_doc: None,
             start_line:0,
-            tab_str: "    ".to_string(),
-            deviation: 0,
-            highlighter: SyntaxHighlighter::new(Editor::get_config())
+            clipboard: Clipboard::new(),
+            search: Search::new(),
+            replacement: String::new(),
+            prompt: None,
+            prompt_input: String::new(),
+            message: None,
+            backup: Backup::None,
+            configs: Configs::new(),
+            theme: Theme::new(),
+            color_mode: ColorMode::detect(),
+            forced_color_mode: None,
+            config_dirs: config::user_dirs(),
+            config_file: None,
+            config_stamps: Vec::new()
+        };
+        editor.load_config();
+        editor
+    }
+
+    // Rebuilds the grammars and settings from the built-in config, then `syntax.txt` and `settings.toml` in each
+    // config directory and finally the `--config` file, each layered over the ones before. Documents in a
+    // project get their own copy with the project's directory layered over the user's ones.
+    pub fn load_config(&mut self) {
+        let mut errors = Vec::new();
+        let mut files = Vec::new();
+
+        let mut configs = Configs::new();
+        configs.base = self.load_configuration(&self.config_dirs, &mut errors, &mut files);
+        for dir in self.open_docs.iter().filter_map(|doc| doc.project.as_ref()) {
+            if !configs.projects.contains_key(dir) {
+                let dirs: Vec<PathBuf> = self.config_dirs.iter().chain(Some(dir)).cloned().collect();
+                let configuration = self.load_configuration(&dirs, &mut errors, &mut files);
+                configs.projects.insert(dir.clone(), configuration);
+            }
+        }
+
+        self.configs = configs;
+        for doc in self.open_docs.iter_mut() {
+            doc.highlights.clear();
+        }
+        self.update_tab_widths();
+        self.apply_color_mode();
+
+        self.config_stamps = files.into_iter().map(|path| {
+            let stamp = config::modified(&path);
+            (path,stamp)
+        }).collect();
+
+        match errors.len() {
+            0 => {},
+            1 => self.set_message(MessageKind::Error, errors.remove(0)),
+            n => self.set_message(MessageKind::Error, format!("{} (and {} more)",errors[0],n - 1))
         }
     }
-	
-	fn get_config() -> String {
-		let mut dir = std::env::current_exe().expect("Couldn't get exe location!");
-		dir.pop();
-		dir.push("Config");
-		dir.push("syntax.txt");
-		std::fs::read_to_string(dir.to_str().expect("Couldn't get syntax location!")).expect("Syntax config not found!")
-	}
 
-    pub fn open(&mut self,path: String) {
-        let (dev,doc) = Document::from_file(path);
-        self.open_docs.push(doc);
-        self.deviation += dev;
+    // Builds the grammars and settings from `dirs` and the `--config` file. Errors and the files looked at
+    // are added to the lists, once each, as the user's files are read again for every project.
+    fn load_configuration(&self,dirs: &[PathBuf],errors: &mut Vec<String>,files: &mut Vec<PathBuf>) -> Configuration {
+        let mut found = Vec::new();
+        let mut highlighter = SyntaxHighlighter::new();
+        found.extend(highlighter.load("built-in config", config::DEFAULT_SYNTAX));
+        let mut settings_layers = vec![("built-in settings".to_string(),config::DEFAULT_SETTINGS.to_string())];
+
+        let config_is_settings = self.config_file.as_ref().is_some_and(|path| path.extension().is_some_and(|ext| ext == "toml"));
+
+        let mut syntax_files: Vec<PathBuf> = dirs.iter().map(|dir| dir.join("syntax.txt")).collect();
+        if !config_is_settings {
+            syntax_files.extend(self.config_file.clone());
+        }
+        for path in &syntax_files {
+            match config::read(path) {
+                Ok(Some(text)) => found.extend(highlighter.load(&path.display().to_string(), &text)),
+                Ok(None) if Some(path) == self.config_file.as_ref() => found.push(format!("{}: file not found",path.display())),
+                Ok(None) => {},
+                Err(err) => found.push(err.to_string())
+            }
+        }
+
+        let mut settings_files: Vec<PathBuf> = dirs.iter().map(|dir| dir.join("settings.toml")).collect();
+        if config_is_settings {
+            settings_files.extend(self.config_file.clone());
+        }
+        for path in &settings_files {
+            match config::read(path) {
+                Ok(Some(text)) => settings_layers.push((path.display().to_string(),text)),
+                Ok(None) if Some(path) == self.config_file.as_ref() => found.push(format!("{}: file not found",path.display())),
+                Ok(None) => {},
+                Err(err) => found.push(err.to_string())
+            }
+        }
+        let (settings,settings_errors) = SettingsTable::load(&settings_layers);
+        found.extend(settings_errors);
+
+        for error in found {
+            if !errors.contains(&error) {
+                errors.push(error);
+            }
+        }
+        for path in syntax_files.into_iter().chain(settings_files) {
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+
+        Configuration {
+            highlighter,
+            settings
+        }
+    }
+
+    // Works out each document's tab stops again, after its path or the settings change.
+    fn update_tab_widths(&mut self) {
+        for doc in self.open_docs.iter_mut() {
+            doc.tab_width = doc.editorconfig.tab_width(self.configs.settings(doc));
+        }
+    }
+
+    // The terminal is shared by every document, so only the user's settings decide its color mode.
+    fn apply_color_mode(&mut self) {
+        let setting = &self.configs.base.settings.base().color_mode;
+        self.color_mode = match self.forced_color_mode {
+            Some(mode) => mode,
+            None if setting == "auto" => ColorMode::detect(),
+            None => match ColorMode::from_name(setting) {
+                Some(mode) => mode,
+                None => {
+                    self.set_message(MessageKind::Error, format!("Unknown color_mode '{}'",setting));
+                    ColorMode::detect()
+                }
+            }
+        };
+    }
+
+    pub fn set_forced_color_mode(&mut self,mode: ColorMode) {
+        self.forced_color_mode = Some(mode);
+        self.apply_color_mode();
+    }
+
+    // Loads the config again if any of its files were changed, created or deleted since the last load.
+    // Returns whether it did.
+    pub fn reload_if_changed(&mut self) -> bool {
+        if self.config_stamps.iter().all(|(path,stamp)| config::modified(path) == *stamp) {
+            return false;
+        }
+        self.load_config();
+        true
+    }
+
+    // Columns taken up by the line numbers and the space after them.
+    fn gutter_width(&self,doc: &Document) -> usize {
+        match self.configs.settings(doc).line_numbers {
+            LineNumbers::Off => 0,
+            _ => doc.line_count().to_string().len().max(3) + 1
+        }
+    }
+
+    // Switches to `themes/<name>.theme` from the highest priority config directory that has it,
+    // or back to the built-in theme for `default`.
+    pub fn set_theme(&mut self,name: &str) -> Result<(),EditorError> {
+        if name == "default" {
+            self.theme = Theme::new();
+            return Ok(());
+        }
+
+        let file = format!("{}.theme",name);
+        let project = self.currently_open_doc.and_then(|doc| self.open_docs[doc].project.as_ref());
+        let path = project.into_iter().chain(self.config_dirs.iter().rev())
+            .map(|dir| dir.join("themes").join(&file))
+            .find(|path| path.is_file())
+            .ok_or_else(|| EditorError::Config(format!("No theme named {}",name)))?;
+        self.theme = Theme::load(&path)?;
+        Ok(())
+    }
+
+    fn scope_style(&self,doc: &Document,scope: Scope) -> Style {
+        match scope {
+            Scope::Rgb(color) => Style::new().fg(color),
+            Scope::Named(index) => self.theme.style(self.configs.for_doc(doc).highlighter.scope_name(index))
+        }
+    }
+
+    pub fn open(&mut self,path: String) -> Result<(),EditorError> {
+        self.open_docs.push(Document::from_file(path)?);
+        self.load_missing_projects();
+        self.update_tab_widths();
+        Ok(())
+    }
+
+    // Loads the config again if a document is in a project that doesn't have one yet.
+    fn load_missing_projects(&mut self) {
+        let configs = &self.configs;
+        if self.open_docs.iter().filter_map(|doc| doc.project.as_ref()).any(|dir| !configs.projects.contains_key(dir)) {
+            self.load_config();
+        }
+    }
+
+    // Writes a copy of every modified document to the recovery directory. Each one gives where it went,
+    // or why it couldn't be written, so no buffer is lost without a word.
+    pub fn write_recovery_files(&self) -> Vec<Result<String,String>> {
+        let dir = save::recovery_dir();
+        let dir_error = std::fs::create_dir_all(&dir).err();
+
+        let stamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+
+        let mut recovered = Vec::new();
+        for (i,doc) in self.open_docs.iter().enumerate().filter(|(_,doc)| doc.modified) {
+            let name = std::path::Path::new(&doc.path).file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| "untitled".to_string());
+            if let Some(err) = &dir_error {
+                recovered.push(Err(format!("{}: {}: {}",name,dir.display(),err)));
+                continue;
+            }
+            let path = dir.join(format!("{}.{}-{}.recovered",name,stamp,i));
+
+            // The document's own encoding may not be able to hold what was typed, and UTF-8 always can.
+            let written = save::atomic_write(&path, &Backup::None, |file| format::encode(file, doc.text.chunks(), &doc.format)).or_else(|_| {
+                let mut utf8 = doc.format.clone();
+                utf8.encoding = Encoding::Utf8;
+                utf8.bom = false;
+                save::atomic_write(&path, &Backup::None, |file| format::encode(file, doc.text.chunks(), &utf8))
+            });
+            recovered.push(match written {
+                Ok(()) => Ok(path.display().to_string()),
+                Err(err) => Err(format!("{}: {}",name,err))
+            });
+        }
+        recovered
     }
 
     pub fn draw<T>(&mut self,window: &mut T)
     where T: Drawable {
-        self.highlighter.reset();
-        let start_x = 4;
         let start_y = 1;
 
-        let mut x = start_x;
-        let mut y = start_y;
+        let end_line = self.start_line + self.text_height(window.height()) - start_y;
+        let current_doc = self.currently_open_doc.unwrap();
+        let doc = &mut self.open_docs[current_doc];
+        let projects = &self.configs.projects;
+        let highlighter = match doc.project.as_ref().and_then(|dir| projects.get(dir)) {
+            Some(project) => &project.highlighter,
+            None => &self.configs.base.highlighter
+        };
+        highlighter.update(&doc.text, &doc.file_type, &mut doc.highlights, end_line);
+
+        let doc = &self.open_docs[current_doc];
+        let end_line = doc.line_count().min(end_line);
+        let start_x = self.gutter_width(doc);
+        let line_numbers = self.configs.settings(doc).line_numbers;
+        let current_line = doc.current_line();
+
+        for line in self.start_line..end_line {
+            let y = line - self.start_line + start_y;
+            let number = match line_numbers {
+                LineNumbers::Relative if line != current_line => Some(current_line.abs_diff(line)),
+                LineNumbers::Off => None,
+                _ => Some(line + 1)
+            };
+            if let Some(number) = number {
+                window.put_string(0, y, &format!("{:0>1$}",number,start_x - 1), self.theme.style("ui.gutter"));
+            }
+
+            let text_style = self.theme.style("ui.text");
+            let mut x = start_x;
+            for grapheme in doc.line_text(line).graphemes(true) {
+                if x >= window.width() {
+                    break;
+                }
+                x += window.put_string(x, y, &unicode::symbol_at(grapheme, x - start_x, doc.tab_width), text_style);
+            }
+
+            let line_start = doc.line_start(line);
+            let line_end = line_start + doc.line_len(line);
 
-        let mut line = 1;
-        window.put_string(0, start_y, &format!("{:0>3}", line));
+            for span in doc.highlights.spans(line) {
+                Editor::highlight_span(window, doc, line, (start_x,y), line_start + span.start, line_start + span.end, self.scope_style(doc, span.scope));
+            }
 
-        for cell in &self.open_docs[self.currently_open_doc.unwrap()].cells {
-            match cell {
-                Cell::Char(c) => {
-                    if line - 1 >= self.start_line && line - 1 < self.start_line + window.height() && y - self.start_line > 0 {
-                        window.put_char(x, y - self.start_line, *c);
-                    }
-                    x += 1;
-                },
-                Cell::NewLine => {
-                    y += 1;
-                    x = start_x;
-                    line += 1;
-                    if line - 1 >= self.start_line && line - 1 < self.start_line + window.height() && y - self.start_line > 0 {
-                        window.put_string(0, y- self.start_line, &format!("{:0>3}", line));
+            // Matches belong to the document they were found in, so they're only shown while searching it.
+            if matches!(self.prompt, Some(Prompt::Find { .. }) | Some(Prompt::Replace { .. })) {
+                let current = self.search.current.map(|c| self.search.matches[c]);
+                for &(match_start,match_end) in self.search.matches_in(line_start, line_end + 1) {
+                    let scope = if current == Some((match_start,match_end)) {
+                        "ui.search.current"
                     }
+                    else {
+                        "ui.search"
+                    };
+                    Editor::highlight_span(window, doc, line, (start_x,y), match_start, match_end, self.theme.style(scope));
                 }
             }
 
+            if let Some((sel_start,sel_end)) = doc.selection() {
+                Editor::highlight_span(window, doc, line, (start_x,y), sel_start, sel_end, self.theme.style("ui.selection"));
+            }
         }
-        
-            
-        let mut off_x = 4;
-        let mut doc_num = 0;
-        for doc in &self.open_docs {
-            window.color(off_x, off_x + doc.name.len(), 
-                if doc_num == self.currently_open_doc.unwrap() {
-                    Color::Red
-                }
-                else {
-                    Color::White
-                }
-            );
-            window.put_string(off_x, 0, &doc.name);
-            off_x += doc.name.len() + 2;
-            doc_num += 1;
+
+        if let Some((label,input,suffix)) = self.prompt_line() {
+            let y = window.height() - 2;
+            let x = window.put_string(0, y, &label, self.theme.style("ui.prompt"));
+            window.put_string(x, y, &format!("{}{}",input,suffix), self.theme.style("ui.text"));
+        }
+        else if let Some(message) = &self.message {
+            let y = window.height() - 2;
+            window.put_string(0, y, &message.text, self.theme.style(message.kind.scope()));
         }
 
-        if let Some(current_doc) = self.currently_open_doc {
-            self.highlighter.highlight(window,self.deviation,&self.open_docs[current_doc].file_type);
+        self.draw_status_bar(window);
+
+        let tabbar = self.theme.style("ui.tabbar");
+        window.fill(0, window.width(), tabbar);
+        let mut off_x = start_x;
+        for (doc_num,doc) in self.open_docs.iter().enumerate() {
+            let label = if doc.modified {
+                format!("{} *",doc.name)
+            }
+            else {
+                doc.name.clone()
+            };
+            let style = if doc_num == self.currently_open_doc.unwrap() {
+                tabbar.patch(self.theme.style("ui.tabbar.active"))
+            }
+            else {
+                tabbar
+            };
+            off_x += window.put_string(off_x, 0, &label, style) + 2;
         }
     }
 
     pub fn update<T>(&mut self,screen: &mut T,event: Event) -> UpdateResult
     where T: Drawable {
         if let Some(current_doc) = self.currently_open_doc {
+            match self.prompt {
+                Some(Prompt::Find { origin }) | Some(Prompt::Replace { origin, stage: ReplaceStage::Find }) => {
+                    return self.update_find(screen, event, current_doc, origin);
+                },
+                Some(Prompt::Replace { origin, stage }) => {
+                    return self.update_replace(screen, event, current_doc, origin, stage);
+                },
+                Some(Prompt::SaveChanges { doc }) => {
+                    return self.update_save_changes(event, doc);
+                },
+                Some(Prompt::SaveAs { doc, quitting }) => {
+                    return self.update_save_as(event, doc, quitting);
+                },
+                Some(Prompt::ConfirmOverwrite { doc, quitting }) => {
+                    return self.update_confirm_overwrite(event, doc, quitting);
+                },
+                Some(Prompt::Command) => {
+                    return self.update_command(event, current_doc);
+                },
+                None => {}
+            }
+
             match event {
                 Event::Key(x) =>{
                     match x.code {
                         KeyCode::Char(c) => {
-                            if x.modifiers == KeyModifiers::CONTROL {
-                                if c == 's' {
-                                    self.open_docs[current_doc].save();
+                            if x.modifiers.contains(KeyModifiers::CONTROL) {
+                                let shift = x.modifiers.contains(KeyModifiers::SHIFT) || c.is_ascii_uppercase();
+                                match (c.to_ascii_lowercase(),shift) {
+                                    ('s',false) if !self.open_docs[current_doc].path.is_empty() => {
+                                        self.save_doc(current_doc);
+                                    },
+                                    ('s',_) => self.ask_save_as(current_doc, false),
+                                    ('z',false) => {
+                                        self.open_docs[current_doc].undo();
+                                    },
+                                    ('z',true) | ('y',_) => {
+                                        self.open_docs[current_doc].redo();
+                                    },
+                                    ('f',false) => {
+                                        let origin = self.open_docs[current_doc].cursor_pos;
+                                        self.search.update(&self.open_docs[current_doc].text);
+                                        self.search.first_from(origin);
+                                        self.prompt = Some(Prompt::Find { origin });
+                                    },
+                                    ('h',false) => {
+                                        let origin = self.open_docs[current_doc].cursor_pos;
+                                        self.search.update(&self.open_docs[current_doc].text);
+                                        self.search.first_from(origin);
+                                        self.prompt = Some(Prompt::Replace { origin, stage: ReplaceStage::Find });
+                                    },
+                                    ('p',false) => {
+                                        self.prompt_input.clear();
+                                        self.prompt = Some(Prompt::Command);
+                                    },
+                                    ('c',false) => {
+                                        if let Some(text) = self.open_docs[current_doc].selected_text() {
+                                            self.clipboard.copy(text);
+                                        }
+                                    },
+                                    ('x',false) => {
+                                        if let Some(text) = self.open_docs[current_doc].selected_text() {
+                                            self.clipboard.copy(text);
+                                            self.open_docs[current_doc].delete_selection();
+                                        }
+                                    },
+                                    ('v',false) => {
+                                        let doc = &mut self.open_docs[current_doc];
+                                        doc.delete_selection();
+                                        doc.history.seal();
+                                        doc.insert_str(self.clipboard.paste());
+                                        doc.history.seal();
+                                    },
+                                    _ => {}
                                 }
 
+                                self.scroll_to_cursor(screen.height());
                                 return UpdateResult::Draw;
                             }
 
-                            if c == '(' {
-                                self.open_docs[current_doc].insert(Cell::Char('('));
-                                self.open_docs[current_doc].insert(Cell::Char(')'));
-                                self.open_docs[current_doc].cursor_pos -= 1;
-                            }
-                            else if c == ')' {
-                                if self.open_docs[current_doc].cursor_pos < self.open_docs[current_doc].cells.len() && self.open_docs[current_doc].cells[self.open_docs[current_doc].cursor_pos] == Cell::Char(')') {
-                                    self.open_docs[current_doc].cursor_pos += 1;
-                                }
-                                else {
-                                    self.open_docs[current_doc].insert(Cell::Char(')'));
-                                }
-                            }
-                            else if c == '{' {
-                                self.open_docs[current_doc].insert(Cell::Char('{'));
-                                self.open_docs[current_doc].insert(Cell::Char('}'));
-                                self.open_docs[current_doc].cursor_pos -= 1;
-                            }
-                            else if c == '}' {
-                                if self.open_docs[current_doc].cursor_pos < self.open_docs[current_doc].cells.len() && self.open_docs[current_doc].cells[self.open_docs[current_doc].cursor_pos] == Cell::Char('}') {
-                                    self.open_docs[current_doc].cursor_pos += 1;
-                                }
-                                else {
-                                    self.open_docs[current_doc].insert(Cell::Char('}'));
-                                }
-                            }
-                            else if c == '[' {
-                                self.open_docs[current_doc].insert(Cell::Char('['));
-                                self.open_docs[current_doc].insert(Cell::Char(']'));
-                                self.open_docs[current_doc].cursor_pos -= 1;
+                            self.open_docs[current_doc].delete_selection();
+
+                            let doc = &mut self.open_docs[current_doc];
+                            let settings = self.configs.settings(doc);
+                            if settings.auto_pair && settings.is_closing_pair(c) && doc.cell_at(doc.cursor_pos) == Some(Cell::Char(c)) {
+                                doc.cursor_pos += 1;
                             }
-                            else if c == ']' {
-                                if self.open_docs[current_doc].cursor_pos < self.open_docs[current_doc].cells.len() && self.open_docs[current_doc].cells[self.open_docs[current_doc].cursor_pos] == Cell::Char(']') {
-                                    self.open_docs[current_doc].cursor_pos += 1;
-                                }
-                                else {
-                                    self.open_docs[current_doc].insert(Cell::Char(']'));
-                                }
+                            else if let Some(close) = settings.closing_pair(c).filter(|_| settings.auto_pair) {
+                                doc.insert(Cell::Char(c));
+                                doc.insert(Cell::Char(close));
+                                doc.cursor_pos -= 1;
                             }
                             else {
-                                self.open_docs[current_doc].insert(Cell::Char(c));
+                                doc.insert(Cell::Char(c));
                             }
                         },
                         KeyCode::Tab => {
-                            for c in self.tab_str.chars() {
-                                self.open_docs[current_doc].insert(Cell::Char(c));
+                            let doc = &mut self.open_docs[current_doc];
+                            doc.delete_selection();
+                            for c in doc.editorconfig.indent(self.configs.settings(doc)).chars() {
+                                doc.insert(Cell::Char(c));
+                            }
+                        },
+                        KeyCode::Left | KeyCode::Right | KeyCode::Up | KeyCode::Down if x.modifiers == KeyModifiers::SHIFT => {
+                            let doc = &mut self.open_docs[current_doc];
+                            doc.start_selection();
+                            match x.code {
+                                KeyCode::Left => doc.move_cursor_left(),
+                                KeyCode::Right => doc.move_cursor_right(),
+                                KeyCode::Up => doc.move_cursor_up(),
+                                _ => doc.move_cursor_down()
                             }
                         },
                         KeyCode::Left => {
                             if x.modifiers == KeyModifiers::empty() {
+                                self.open_docs[current_doc].clear_selection();
                                 self.open_docs[current_doc].move_cursor_left();
 
                             }
-                            else {
-                                if let Some(c_doc) = self.currently_open_doc {
-                                    if c_doc != 0 {
-                                        self.currently_open_doc = Some(c_doc - 1);
-                                    }
-                                }
+                            else if current_doc != 0 {
+                                self.currently_open_doc = Some(current_doc - 1);
                             }
                         },
                         KeyCode::Right => { 
                             if x.modifiers == KeyModifiers::empty() {
+                                self.open_docs[current_doc].clear_selection();
                                 self.open_docs[current_doc].move_cursor_right(); 
                                 
                             }
-                            else {
-                                if let Some(c_doc) = self.currently_open_doc {
-                                    if c_doc + 1 < self.open_docs.len() {
-                                        self.currently_open_doc = Some(c_doc + 1);
-                                    }
-                                }
+                            else if current_doc + 1 < self.open_docs.len() {
+                                self.currently_open_doc = Some(current_doc + 1);
                             }
                         },
-                        KeyCode::Up => self.open_docs[current_doc].move_cursor_up(),
-                        KeyCode::Down => self.open_docs[current_doc].move_cursor_down(),
-                        KeyCode::Enter => self.open_docs[current_doc].insert(Cell::NewLine),
-                        KeyCode::Backspace => {
-                            if self.open_docs[current_doc].cursor_pos != 0 {
-                                let pos = self.open_docs[current_doc].cursor_pos - 1;
-                                self.open_docs[current_doc].delete(pos);
-                                self.open_docs[current_doc].cursor_pos -= 1;
-                            }
-                            else {
-                                return UpdateResult::NOp;
-                            }
+                        KeyCode::Up => {
+                            self.open_docs[current_doc].clear_selection();
+                            self.open_docs[current_doc].move_cursor_up();
                         },
-                        KeyCode::Delete => {
-                            if self.open_docs[current_doc].cursor_pos < self.open_docs[current_doc].cells.len() {
-                                let pos = self.open_docs[current_doc].cursor_pos;
-                                self.open_docs[current_doc].delete(pos);
-                            }
-                            else {
-                                return UpdateResult::NOp;
-                            }
+                        KeyCode::Down => {
+                            self.open_docs[current_doc].clear_selection();
+                            self.open_docs[current_doc].move_cursor_down();
+                        },
+                        KeyCode::Enter => {
+                            self.open_docs[current_doc].delete_selection();
+                            self.open_docs[current_doc].insert(Cell::NewLine);
                         },
+                        KeyCode::Backspace | KeyCode::Delete if self.open_docs[current_doc].selection().is_some() => {
+                            self.open_docs[current_doc].delete_selection();
+                        },
+                        KeyCode::Backspace if self.open_docs[current_doc].cursor_pos != 0 => {
+                            let doc = &mut self.open_docs[current_doc];
+                            let pos = doc.prev_grapheme(doc.cursor_pos);
+                            doc.delete_range(pos, doc.cursor_pos);
+                            doc.cursor_pos = pos;
+                        },
+                        KeyCode::Delete if self.open_docs[current_doc].cursor_pos < self.open_docs[current_doc].len() => {
+                            let doc = &mut self.open_docs[current_doc];
+                            let end = doc.next_grapheme(doc.cursor_pos);
+                            doc.delete_range(doc.cursor_pos, end);
+                        },
+                        KeyCode::F(12) => self.ask_save_as(current_doc, false),
                         KeyCode::Esc => {
-                            return UpdateResult::Exit;
+                            return self.ask_save_changes(0);
                         }
                         _ => return UpdateResult::NOp
                     }
@@ -224,73 +643,611 @@ impl Editor {
                     let (width,height) = crossterm::terminal::size().expect("Couldn't get resized size");
                     screen.resize(width.into(), height.into());
                 }
-                Event::Mouse(_) => {
-                    
-                }
+                Event::Mouse(MouseEvent::Down(MouseButton::Left,column,row,_)) => {
+                    if let Some(pos) = self.screen_to_pos(column, row) {
+                        let doc = &mut self.open_docs[current_doc];
+                        doc.history.seal();
+                        doc.cursor_pos = pos;
+                        doc.selection_anchor = Some(pos);
+                    }
+                    else {
+                        return UpdateResult::NOp;
+                    }
+                },
+                Event::Mouse(MouseEvent::Drag(MouseButton::Left,column,row,_)) => {
+                    if let Some(pos) = self.screen_to_pos(column, row) {
+                        let doc = &mut self.open_docs[current_doc];
+                        doc.start_selection();
+                        doc.cursor_pos = pos;
+                    }
+                    else {
+                        return UpdateResult::NOp;
+                    }
+                },
+                Event::Mouse(_) => return UpdateResult::NOp
             }
 
-            if self.open_docs[current_doc].current_line() < self.start_line {
-                self.start_line -= 1;
+            self.scroll_to_cursor(screen.height());
+        }
+
+        UpdateResult::Draw
+    }
+
+    // Styles the part of [start, end) that falls on `line`, whose text starts on screen at `origin`.
+    fn highlight_span<T>(window: &mut T,doc: &Document,line: usize,origin: (usize,usize),start: usize,end: usize,style: Style)
+    where T: Drawable {
+        let (start_x,y) = origin;
+
+        let line_start = doc.line_start(line);
+        let line_end = line_start + doc.line_len(line);
+        let from = start.max(line_start);
+        let to = end.min(line_end + 1);
+        if from < to {
+            let from_x = doc.column_in(line, from);
+            // Line breaks inside the span are shown as one extra highlighted column.
+            let to_x = if to > line_end { doc.column_in(line, line_end) + 1 } else { doc.column_in(line, to) };
+            let row = y * window.width();
+            let width = window.width();
+            window.fill(row + (start_x + from_x).min(width), row + (start_x + to_x).min(width), style);
+        }
+    }
+
+    // Bottom row: path and modified flag on the left, cursor position and file format on the right.
+    fn draw_status_bar<T>(&self,window: &mut T)
+    where T: Drawable {
+        let doc = &self.open_docs[self.currently_open_doc.unwrap()];
+        let y = window.height() - 1;
+        let row = y * window.width();
+
+        let left = format!(" {}{}",doc.path,if doc.modified { " [+]" } else { "" });
+        let right = format!("Ln {}, Col {} | {} | {} ",
+            doc.current_line() + 1,
+            doc.current_column() + 1,
+            doc.file_type.as_deref().unwrap_or("plain"),
+            doc.format_name());
+
+        let style = self.theme.style("ui.statusbar");
+        window.fill(row, row + window.width(), style);
+        window.put_string(0, y, &left, style);
+        let right_x = window.width().saturating_sub(unicode::str_width(&right)).max(unicode::str_width(&left) + 1);
+        window.put_string(right_x, y, &right, style);
+    }
+
+    fn report_replaced(&mut self,replaced: usize) {
+        if replaced == 0 {
+            self.set_message(MessageKind::Warn, "No occurrences replaced".to_string());
+        }
+        else {
+            self.set_message(MessageKind::Info, format!("Replaced {} occurrence(s)",replaced));
+        }
+    }
+
+    pub fn set_message(&mut self,kind: MessageKind,text: String) {
+        self.message = Some(Message::new(kind, text));
+    }
+
+    // Drops the message once it has timed out, returning whether the screen needs redrawing.
+    pub fn expire_message(&mut self) -> bool {
+        if self.message.as_ref().map(Message::expired).unwrap_or(false) {
+            self.message = None;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    fn save_doc(&mut self,doc: usize) -> bool {
+        match self.open_docs[doc].save(&self.backup) {
+            Ok(()) => {
+                self.set_message(MessageKind::Info, format!("Saved {}",self.open_docs[doc].path));
+                true
+            },
+            Err(err) => {
+                self.set_message(MessageKind::Error, format!("Couldn't save {}: {}",self.open_docs[doc].path,err));
+                false
             }
-            else if self.open_docs[current_doc].current_line() - self.start_line + 1 >= screen.height() {
-                self.start_line += 1;
+        }
+    }
+
+    // Screen rows left for the tab bar and the document once the bottom bars are drawn.
+    fn text_height(&self,height: usize) -> usize {
+        if self.prompt.is_some() || self.message.is_some() {
+            height - 2
+        }
+        else {
+            height - 1
+        }
+    }
+
+    fn update_find<T>(&mut self,screen: &mut T,event: Event,current_doc: usize,origin: usize) -> UpdateResult
+    where T: Drawable {
+        let key = match event {
+            Event::Key(key) => key,
+            Event::Resize(width,height) => {
+                screen.resize(width.into(), height.into());
+                return UpdateResult::Draw;
+            },
+            _ => return UpdateResult::NOp
+        };
+
+        let cursor = self.open_docs[current_doc].cursor_pos;
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        let found = match key.code {
+            KeyCode::Esc => {
+                self.prompt = None;
+                return UpdateResult::Draw;
+            },
+            KeyCode::Enter if matches!(self.prompt, Some(Prompt::Replace { .. })) => {
+                self.prompt = Some(Prompt::Replace { origin, stage: ReplaceStage::With });
+                return UpdateResult::Draw;
+            },
+            KeyCode::Enter | KeyCode::F(3) if shift => self.search.prev(cursor),
+            KeyCode::Enter | KeyCode::F(3) => self.search.next(cursor),
+            KeyCode::Tab => {
+                self.search.mode = self.search.mode.next();
+                self.search.update(&self.open_docs[current_doc].text);
+                self.search.first_from(origin)
+            },
+            KeyCode::Backspace => {
+                self.search.query.pop();
+                self.search.update(&self.open_docs[current_doc].text);
+                self.search.first_from(origin)
+            },
+            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.search.query.push(c);
+                self.search.update(&self.open_docs[current_doc].text);
+                self.search.first_from(origin)
+            },
+            _ => return UpdateResult::NOp
+        };
+
+        let doc = &mut self.open_docs[current_doc];
+        doc.clear_selection();
+        doc.history.seal();
+        doc.cursor_pos = match found {
+            Some((start,_)) => start,
+            None => origin
+        };
+        self.scroll_to_cursor(screen.height());
+
+        UpdateResult::Draw
+    }
+
+    fn update_replace<T>(&mut self,screen: &mut T,event: Event,current_doc: usize,origin: usize,stage: ReplaceStage) -> UpdateResult
+    where T: Drawable {
+        let key = match event {
+            Event::Key(key) => key,
+            Event::Resize(width,height) => {
+                screen.resize(width.into(), height.into());
+                return UpdateResult::Draw;
+            },
+            _ => return UpdateResult::NOp
+        };
+
+        if let ReplaceStage::Confirm { replaced } = stage {
+            let cursor = self.open_docs[current_doc].cursor_pos;
+            let (replaced,found) = match key.code {
+                KeyCode::Char('y') => {
+                    let replaced = replaced + self.replace_current(current_doc) as usize;
+                    let cursor = self.open_docs[current_doc].cursor_pos;
+                    (replaced,self.search.first_from(cursor).filter(|m| m.0 >= cursor))
+                },
+                KeyCode::Char('n') => (replaced,self.search.next(cursor).filter(|m| m.0 > cursor)),
+                KeyCode::Char('a') => (replaced + self.replace_all(current_doc, cursor),None),
+                KeyCode::Char('q') | KeyCode::Esc => (replaced,None),
+                _ => return UpdateResult::NOp
+            };
+
+            match found {
+                Some((start,_)) => {
+                    self.open_docs[current_doc].cursor_pos = start;
+                    self.prompt = Some(Prompt::Replace { origin, stage: ReplaceStage::Confirm { replaced } });
+                },
+                None => {
+                    self.prompt = None;
+                    self.report_replaced(replaced);
+                }
             }
+            self.scroll_to_cursor(screen.height());
+            return UpdateResult::Draw;
+        }
+
+        match key.code {
+            KeyCode::Esc => self.prompt = None,
+            KeyCode::Enter => {
+                self.replace_current(current_doc);
+                let cursor = self.open_docs[current_doc].cursor_pos;
+                if let Some((start,_)) = self.search.first_from(cursor) {
+                    self.open_docs[current_doc].cursor_pos = start;
+                }
+            },
+            KeyCode::Char('a') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                let replaced = self.replace_all(current_doc, 0);
+                self.prompt = None;
+                self.report_replaced(replaced);
+            },
+            KeyCode::Char('e') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                let cursor = self.open_docs[current_doc].cursor_pos;
+                if let Some((start,_)) = self.search.first_from(cursor) {
+                    self.open_docs[current_doc].cursor_pos = start;
+                    self.prompt = Some(Prompt::Replace { origin, stage: ReplaceStage::Confirm { replaced: 0 } });
+                }
+            },
+            KeyCode::Backspace => {
+                self.replacement.pop();
+            },
+            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => self.replacement.push(c),
+            _ => return UpdateResult::NOp
         }
 
+        self.scroll_to_cursor(screen.height());
         UpdateResult::Draw
     }
 
-    pub fn make_new_doc(&mut self,doc_name: String) {
-        self.open_docs.push(Document::new(doc_name,"path".to_string()));
-        if self.currently_open_doc.is_none() {
-            self.currently_open_doc = Some(self.open_docs.len() - 1);
+    // Prompts for the first modified document at or after `from`, exiting once there are none left.
+    fn ask_save_changes(&mut self,from: usize) -> UpdateResult {
+        match (from..self.open_docs.len()).find(|&doc| self.open_docs[doc].modified) {
+            Some(doc) => {
+                self.currently_open_doc = Some(doc);
+                self.prompt = Some(Prompt::SaveChanges { doc });
+                UpdateResult::Draw
+            },
+            None => UpdateResult::Exit
+        }
+    }
+
+    fn update_save_changes(&mut self,event: Event,doc: usize) -> UpdateResult {
+        let key = match event {
+            Event::Key(key) => key,
+            _ => return UpdateResult::NOp
+        };
+
+        match key.code {
+            KeyCode::Char('y') if self.open_docs[doc].path.is_empty() => {
+                self.ask_save_as(doc, true);
+                UpdateResult::Draw
+            },
+            KeyCode::Char('y') => {
+                if self.save_doc(doc) {
+                    self.ask_save_changes(doc + 1)
+                }
+                else {
+                    self.prompt = None;
+                    UpdateResult::Draw
+                }
+            },
+            KeyCode::Char('n') => self.ask_save_changes(doc + 1),
+            KeyCode::Char('c') | KeyCode::Esc => {
+                self.prompt = None;
+                UpdateResult::Draw
+            },
+            _ => UpdateResult::NOp
+        }
+    }
+
+    fn update_command(&mut self,event: Event,current_doc: usize) -> UpdateResult {
+        let key = match event {
+            Event::Key(key) => key,
+            _ => return UpdateResult::NOp
+        };
+
+        match key.code {
+            KeyCode::Esc => self.prompt = None,
+            KeyCode::Enter => {
+                self.prompt = None;
+                let command = std::mem::take(&mut self.prompt_input);
+                return self.run_command(current_doc, &command);
+            },
+            KeyCode::Backspace => {
+                self.prompt_input.pop();
+            },
+            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => self.prompt_input.push(c),
+            _ => return UpdateResult::NOp
+        }
+
+        UpdateResult::Draw
+    }
+
+    fn run_command(&mut self,current_doc: usize,command: &str) -> UpdateResult {
+        let mut words = command.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let arg = words.next().unwrap_or("");
+        let doc = &mut self.open_docs[current_doc];
+
+        let on_off = match arg {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None
+        };
+
+        match (name,on_off) {
+            ("eol",_) => match LineEnding::from_name(arg) {
+                Some(line_ending) => doc.set_line_ending(line_ending),
+                None => self.set_message(MessageKind::Error, "Usage: eol lf|crlf".to_string())
+            },
+            ("encoding",_) => match Encoding::from_name(arg) {
+                Some(encoding) => {
+                    doc.format.encoding = encoding;
+                    doc.format_changed();
+                },
+                None => self.set_message(MessageKind::Error, "Usage: encoding utf-8|utf-16le|utf-16be|latin-1".to_string())
+            },
+            ("bom",Some(bom)) => {
+                doc.format.bom = bom;
+                doc.format_changed();
+            },
+            ("final-newline",Some(final_newline)) => {
+                doc.format.final_newline = final_newline;
+                doc.format_changed();
+            },
+            ("saveas",_) => {
+                self.ask_save_as(current_doc, false);
+                if !arg.is_empty() {
+                    self.prompt_input = arg.to_string();
+                }
+            },
+            ("reload",_) => self.load_config(),
+            ("theme",_) => {
+                if let Err(err) = self.set_theme(arg) {
+                    self.set_message(MessageKind::Error, err.to_string());
+                }
+            },
+            ("",_) => {},
+            _ => self.set_message(MessageKind::Error, format!("Unknown command: {}",command))
+        }
+
+        UpdateResult::Draw
+    }
+
+    fn ask_save_as(&mut self,doc: usize,quitting: bool) {
+        self.prompt_input = self.open_docs[doc].path.clone();
+        self.prompt = Some(Prompt::SaveAs { doc, quitting });
+    }
+
+    fn update_save_as(&mut self,event: Event,doc: usize,quitting: bool) -> UpdateResult {
+        let key = match event {
+            Event::Key(key) => key,
+            _ => return UpdateResult::NOp
+        };
+
+        match key.code {
+            KeyCode::Esc => self.prompt = None,
+            KeyCode::Enter if self.prompt_input.is_empty() => return UpdateResult::NOp,
+            KeyCode::Enter => {
+                if self.prompt_input != self.open_docs[doc].path && std::path::Path::new(&self.prompt_input).exists() {
+                    self.prompt = Some(Prompt::ConfirmOverwrite { doc, quitting });
+                }
+                else {
+                    return self.save_as(doc, quitting);
+                }
+            },
+            KeyCode::Backspace => {
+                self.prompt_input.pop();
+            },
+            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => self.prompt_input.push(c),
+            _ => return UpdateResult::NOp
+        }
+
+        UpdateResult::Draw
+    }
+
+    fn update_confirm_overwrite(&mut self,event: Event,doc: usize,quitting: bool) -> UpdateResult {
+        match event {
+            Event::Key(key) => match key.code {
+                KeyCode::Char('y') => self.save_as(doc, quitting),
+                KeyCode::Char('n') | KeyCode::Esc => {
+                    self.prompt = Some(Prompt::SaveAs { doc, quitting });
+                    UpdateResult::Draw
+                },
+                _ => UpdateResult::NOp
+            },
+            _ => UpdateResult::NOp
+        }
+    }
+
+    // Rebinds the document to the typed path and saves it there, going back to its old path on failure.
+    fn save_as(&mut self,doc: usize,quitting: bool) -> UpdateResult {
+        let old_path = self.open_docs[doc].path.clone();
+        let old_name = self.open_docs[doc].name.clone();
+
+        self.open_docs[doc].set_path(self.prompt_input.clone());
+        self.prompt = None;
+
+        if self.save_doc(doc) {
+            if quitting {
+                return self.ask_save_changes(doc + 1);
+            }
+        }
+        else {
+            self.open_docs[doc].set_path(old_path);
+            self.open_docs[doc].name = old_name;
+        }
+        self.load_missing_projects();
+        self.update_tab_widths();
+
+        UpdateResult::Draw
+    }
+
+    // Replaces the current search match, leaving the cursor after the inserted text.
+    fn replace_current(&mut self,current_doc: usize) -> bool {
+        let target = match self.search.current {
+            Some(current) => self.search.matches[current],
+            None => return false
+        };
+
+        let doc = &mut self.open_docs[current_doc];
+        let replacement = self.search.replacements(&doc.text, &self.replacement).into_iter().find(|r| (r.0,r.1) == target);
+        if let Some((start,end,with)) = replacement {
+            doc.clear_selection();
+            doc.replace_range(start, end, &with);
+            self.search.update(&doc.text);
+            true
+        }
+        else {
+            false
         }
     }
 
-    pub fn update_cursor(&mut self) {
-        let start_x = 4;
+    // Replaces every match starting at or after `from` as a single undoable edit.
+    fn replace_all(&mut self,current_doc: usize,from: usize) -> usize {
+        let doc = &mut self.open_docs[current_doc];
+        let replacements = self.search.replacements(&doc.text, &self.replacement);
+
+        doc.clear_selection();
+        doc.history.begin_group(doc.cursor_pos);
+        let mut replaced = 0;
+        for (start,end,with) in replacements.iter().rev().filter(|r| r.0 >= from) {
+            doc.replace_range(*start, *end, with);
+            replaced += 1;
+        }
+        doc.history.end_group();
+
+        self.search.update(&doc.text);
+        replaced
+    }
+
+    // The prompt shown on the bottom row as (label, input, trailing info), if one is open.
+    fn prompt_line(&self) -> Option<(String,String,String)> {
+        let matches = if self.search.invalid {
+            " [invalid regex]".to_string()
+        }
+        else if let Some(current) = self.search.current {
+            format!(" [{}/{}]",current + 1,self.search.matches.len())
+        }
+        else if !self.search.query.is_empty() {
+            " [no matches]".to_string()
+        }
+        else {
+            String::new()
+        };
+
+        match self.prompt.as_ref()? {
+            Prompt::Find { .. } => Some((format!("Find ({}): ",self.search.mode.name()),self.search.query.clone(),matches)),
+            Prompt::Replace { stage: ReplaceStage::Find, .. } => {
+                Some((format!("Replace ({}): ",self.search.mode.name()),self.search.query.clone(),matches))
+            },
+            Prompt::Replace { stage: ReplaceStage::With, .. } => {
+                Some(("With: ".to_string(),self.replacement.clone(),format!("{}  Enter: next  ^A: all  ^E: confirm each",matches)))
+            },
+            Prompt::Replace { stage: ReplaceStage::Confirm { .. }, .. } => {
+                Some(("Replace this match? (y/n/a/q) ".to_string(),String::new(),matches))
+            },
+            Prompt::SaveChanges { doc } => {
+                Some((format!("Save changes to {}? (y)es (n)o (c)ancel ",self.open_docs[*doc].name),String::new(),String::new()))
+            },
+            Prompt::Command => Some((": ".to_string(),self.prompt_input.clone(),String::new())),
+            Prompt::SaveAs { .. } => Some(("Save as: ".to_string(),self.prompt_input.clone(),String::new())),
+            Prompt::ConfirmOverwrite { .. } => {
+                Some((format!("{} already exists. Overwrite? (y/n) ",self.prompt_input),String::new(),String::new()))
+            }
+        }
+    }
+
+    fn screen_to_pos(&self,column: u16,row: u16) -> Option<usize> {
         let start_y = 1;
 
-        let mut x = start_x;
-        let mut y = start_y;
+        let doc = &self.open_docs[self.currently_open_doc?];
+        let start_x = self.gutter_width(doc);
+        if (row as usize) < start_y {
+            return None;
+        }
 
-        let mut i = 0;
+        let line = self.start_line + row as usize - start_y;
+        let column = (column as usize).saturating_sub(start_x);
+        Some(doc.pos_at(line, column))
+    }
 
-        for cell in &self.open_docs[self.currently_open_doc.unwrap()].cells {
-            if i >= self.open_docs[self.currently_open_doc.unwrap()].cursor_pos {
-                break;
+    // Scrolls so the cursor's line, along with `scroll_margin` lines around it, is on screen.
+    fn scroll_to_cursor(&mut self,height: usize) {
+        let visible = self.text_height(height) - 1;
+        if let Some(current_doc) = self.currently_open_doc {
+            let doc = &self.open_docs[current_doc];
+            let line = doc.current_line();
+            let margin = self.configs.settings(doc).scroll_margin.min(visible.saturating_sub(1) / 2);
+            let below = (line + margin).min(doc.line_count() - 1);
+            if line < self.start_line + margin {
+                self.start_line = line.saturating_sub(margin);
             }
-            match cell {
-                Cell::NewLine => {
-                    x = start_x;
-                    y += 1;
-                },
-                Cell::Char(_) => x += 1
+            else if below >= self.start_line + visible {
+                self.start_line = below + 1 - visible;
             }
-            i += 1;
         }
+    }
 
-        y -= self.start_line as u16;
+    pub fn make_new_doc(&mut self,doc_name: String) {
+        self.open_docs.push(Document::new(doc_name,String::new()));
+        self.update_tab_widths();
+        if self.currently_open_doc.is_none() {
+            self.currently_open_doc = Some(self.open_docs.len() - 1);
+        }
+    }
 
-        print!("{}",crossterm::cursor::MoveTo(x,y));
+    pub fn update_cursor(&mut self,height: usize) {
+        let start_y = 1;
+
+        if let Some((label,input,_)) = self.prompt_line() {
+            let x = unicode::str_width(&label) + unicode::str_width(&input);
+            print!("{}",crossterm::cursor::MoveTo(x as u16,(height - 2) as u16));
+            stdout().flush().unwrap();
+            return;
+        }
+
+        let doc = &self.open_docs[self.currently_open_doc.unwrap()];
+
+        let x = self.gutter_width(doc) + doc.current_column();
+        let y = start_y + doc.current_line() - self.start_line;
+
+        print!("{}",crossterm::cursor::MoveTo(x as u16,y as u16));
         stdout().flush().unwrap();
     }
 
     
 }
 
-#[derive(Debug,PartialEq)]
+#[derive(Debug,PartialEq,Clone,Copy)]
 pub enum Cell {
     Char(char),
     NewLine,
 }
 
+impl From<char> for Cell {
+    fn from(c: char) -> Self {
+        if c == '\n' {
+            Cell::NewLine
+        }
+        else {
+            Cell::Char(c)
+        }
+    }
+}
+
+impl From<Cell> for char {
+    fn from(cell: Cell) -> Self {
+        match cell {
+            Cell::Char(c) => c,
+            Cell::NewLine => '\n'
+        }
+    }
+}
+
 pub struct Document {
-    pub cells: Vec<Cell>,
+    pub text: Rope,
     pub cursor_pos: usize,
     pub name: String,
     pub file_type: Option<String>,
-    pub path: String
+    pub path: String,
+    pub history: History,
+    pub selection_anchor: Option<usize>,
+    pub modified: bool,
+    pub format: FileFormat,
+    pub highlights: HighlightCache,
+    // The `.editorconfig` rules for the document's path.
+    pub editorconfig: EditorConfig,
+    // The nearest `.vce` directory above the document's path, whose config applies to it.
+    pub project: Option<PathBuf>,
+    // Columns between tab stops, kept up to date by the editor from `.editorconfig` and the settings.
+    pub tab_width: usize
 }
 
 use std::collections::HashMap;
@@ -312,212 +1269,423 @@ lazy_static! {
 impl Document {
     pub fn new(name: String,path: String) -> Self {
         Self {
-            cells: Vec::new(),
+            text: Rope::new(),
             cursor_pos: 0,
             name,
             file_type:None,
-            path
+            path,
+            history: History::new(),
+            selection_anchor: None,
+            modified: false,
+            format: FileFormat::new(),
+            highlights: HighlightCache::new(),
+            editorconfig: EditorConfig::default(),
+            tab_width: Settings::default().indent_width,
+            project: None
         }
     }
 
-    pub fn current_line(&self) -> usize {
-        let mut line = 0;
-        for i in 0..self.cursor_pos {
-            if let Cell::NewLine = self.cells[i] {
-                line += 1;
-            }
-        }
-        line
+    pub fn len(&self) -> usize {
+        self.text.len_chars()
     }
 
-    pub fn from_file(path: String) -> (usize,Self) {
-        let mut cells = Vec::new();
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        let file = File::open(&path).expect("File not found");
-        let reader = BufReader::new(file);
+    pub fn cell_at(&self,index: usize) -> Option<Cell> {
+        self.text.get_char(index).map(Cell::from)
+    }
 
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                for c in line.chars() {
-                    cells.push(Cell::Char(c));
-                }
-                cells.push(Cell::NewLine);
+    pub fn line_count(&self) -> usize {
+        self.text.len_lines()
+    }
+
+    pub fn line_start(&self,line: usize) -> usize {
+        self.text.line_to_char(line)
+    }
+
+    // Length of a line in chars, not counting its line break. A '\r\n' pasted into the buffer counts
+    // as one break.
+    pub fn line_len(&self,line: usize) -> usize {
+        let line = self.text.line(line);
+        let mut len = line.len_chars();
+        if len > 0 && line.char(len - 1) == '\n' {
+            len -= 1;
+            if len > 0 && line.char(len - 1) == '\r' {
+                len -= 1;
             }
         }
+        len
+    }
 
-        cells.pop();
+    pub fn current_line(&self) -> usize {
+        self.text.char_to_line(self.cursor_pos)
+    }
 
-        let mut name = std::path::Path::new(&path).file_name().unwrap().to_str().unwrap().to_string();
+    // Screen column of the cursor within its line.
+    pub fn current_column(&self) -> usize {
+        self.column_in(self.current_line(), self.cursor_pos)
+    }
 
-        let mut deviation = 0;
-        let mut file_type = None;
+    // The text of a line without its line break.
+    pub fn line_text(&self,line: usize) -> String {
+        self.text.line(line).chars().take(self.line_len(line)).collect()
+    }
 
-        if let Some(ext) = std::path::Path::new(&path).extension() {
-            if ICON_MAP.contains_key(ext.to_str().unwrap()) {
-                name.push(' ');
-                name.push(ICON_MAP[ext.to_str().unwrap()]);
-                file_type = Some(ext.to_str().unwrap().to_string());
-                deviation = 2;
-            }
+    // Screen column of `pos` counted from the start of `line`, in display widths of grapheme clusters
+    // with tabs expanded.
+    pub fn column_in(&self,line: usize,pos: usize) -> usize {
+        let len = pos.saturating_sub(self.line_start(line)).min(self.line_len(line));
+        let text: String = self.text.line(line).chars().take(len).collect();
+        unicode::line_width(&text, self.tab_width)
+    }
+
+    // Char offsets within a line where its grapheme clusters start, followed by the end of its text.
+    fn grapheme_starts(&self,line: usize) -> Vec<usize> {
+        let text = self.line_text(line);
+        let mut starts = Vec::new();
+        let mut offset = 0;
+        for grapheme in text.graphemes(true) {
+            starts.push(offset);
+            offset += grapheme.chars().count();
         }
+        starts.push(offset);
+        starts
+    }
 
-        (deviation,
-        Self {
-            cells,
-            cursor_pos: 0,
-            name,
-            file_type,
-            path
-        })
+    // Start of the grapheme cluster after the one at `pos`. A line break counts as one cluster.
+    pub fn next_grapheme(&self,pos: usize) -> usize {
+        if pos >= self.len() {
+            return self.len();
+        }
+        let line = self.text.char_to_line(pos);
+        let line_start = self.line_start(line);
+        if pos >= line_start + self.line_len(line) {
+            return self.line_start(line + 1).min(self.len());
+        }
+        let offset = pos - line_start;
+        line_start + self.grapheme_starts(line).into_iter().find(|&start| start > offset).unwrap()
     }
 
-    pub fn save(&self) {
-        if let Ok(mut file) = std::fs::OpenOptions::new().write(true).open(&self.path) {
-            file.set_len(0).unwrap();
+    // Start of the grapheme cluster before `pos`.
+    pub fn prev_grapheme(&self,pos: usize) -> usize {
+        if pos == 0 {
+            return 0;
+        }
+        let mut line = self.text.char_to_line(pos);
+        if pos == self.line_start(line) {
+            line -= 1;
+        }
+        let line_start = self.line_start(line);
+        let line_end = line_start + self.line_len(line);
+        if pos > line_end {
+            return line_end;
+        }
+        let offset = pos - line_start;
+        line_start + self.grapheme_starts(line).into_iter().rev().find(|&start| start < offset).unwrap_or(0)
+    }
 
-            let mut content = String::new();
-            for cell in &self.cells {
-                match cell {
-                    Cell::Char(c) => content.push(*c),
-                    Cell::NewLine => content.push('\n')
-                }
-            }
+    // Loads a document from `path`. A path that doesn't exist yet gives an empty document bound to it.
+    pub fn from_file(path: String) -> Result<Self,EditorError> {
+        let mut doc = Document::new(String::new(), String::new());
+
+        match std::fs::read(&path) {
+            Ok(bytes) => {
+                let (content,format) = format::decode(&bytes);
+                doc.text = Rope::from_str(&content);
+                doc.format = format;
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+            Err(err) => return Err(EditorError::io(&path, err))
+        }
 
-            file.write(content.as_bytes()).expect(&format!("Couldn't access file path {}",&self.path));
+        doc.set_path(path);
+        Ok(doc)
+    }
 
-            Document::error("Saved file!".to_string());
-        }
-        else {
-            Document::error(format!("Couldn't open file {}",&self.path));
+    // Binds the document to `path`, deriving its tab name, icon and file type from it.
+    pub fn set_path(&mut self,path: String) {
+        let file_path = std::path::Path::new(&path);
+        let mut name = file_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_else(|| path.clone());
+
+        // Any extension is a file type, so settings and grammars can be given for ones without an icon.
+        self.file_type = file_path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_string());
+        if let Some(icon) = self.file_type.as_deref().and_then(|ext| ICON_MAP.get(ext)) {
+            name.push(' ');
+            name.push(*icon);
         }
+
+        self.project = if path.is_empty() { None } else { config::project_dir(file_path) };
+        self.name = name;
+        self.path = path;
+        self.apply_editorconfig();
     }
 
-    fn error(err_msg: String) {
-        print!("{}",err_msg);
-        let _ = stdout().flush();
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+    // Looks up the `.editorconfig` rules for the document's path and switches the format it's saved in to match.
+    fn apply_editorconfig(&mut self) {
+        self.editorconfig = EditorConfig::resolve(Path::new(&self.path));
+        let config = self.editorconfig;
+
+        // Only how the file is written changes, not the buffer, so a mixed file opens unmodified with the
+        // line ending it'll be saved with in the status bar.
+        if let Some(line_ending) = config.end_of_line {
+            self.format.line_ending = line_ending;
+            self.format.crlf_lines.clear();
+        }
+        if let Some((encoding,bom)) = config.charset {
+            self.format.encoding = encoding;
+            self.format.bom = bom;
+        }
+        if let Some(final_newline) = config.insert_final_newline {
+            self.format.final_newline = final_newline;
+        }
     }
 
-    pub fn insert(&mut self,cell: Cell) {
-        self.cells.insert(self.cursor_pos, cell);
-        self.cursor_pos += 1;
+    pub fn save(&mut self,backup: &Backup) -> std::io::Result<()> {
+        let trims = if self.editorconfig.trim_trailing_whitespace { self.trailing_whitespace() } else { Vec::new() };
+
+        // The trimmed text is written from a copy, so the buffer is only trimmed once the write worked.
+        let mut text = self.text.clone();
+        for &(start,end) in &trims {
+            text.remove(start..end);
+        }
+        let file_format = &self.format;
+        save::atomic_write(std::path::Path::new(&self.path), backup, |file| format::encode(file, text.chunks(), file_format))?;
+
+        self.remove_ranges(trims);
+        self.history.mark_saved();
+        self.modified = false;
+        Ok(())
     }
 
-    pub fn delete(&mut self,index: usize) {
-        self.cells.remove(index);
+    // The spaces and tabs at the ends of lines, bottom to top, so removing one range doesn't move the
+    // ones still to come.
+    fn trailing_whitespace(&self) -> Vec<(usize,usize)> {
+        (0..self.line_count()).rev().filter_map(|line| {
+            let text = self.line_text(line);
+            let trailing = text.chars().rev().take_while(|&c| c == ' ' || c == '\t').count();
+            let end = self.line_start(line) + self.line_len(line);
+            if trailing > 0 { Some((end - trailing,end)) } else { None }
+        }).collect()
     }
 
-    pub fn move_cursor_right(&mut self) {
-        if self.cursor_pos + 1 <= self.cells.len() {
-            self.cursor_pos += 1;
+    // Deletes ranges given bottom to top as one undo unit, keeping the cursor on the same text.
+    fn remove_ranges(&mut self,ranges: Vec<(usize,usize)>) {
+        if ranges.is_empty() {
+            return;
         }
-    }
 
-    pub fn move_cursor_left(&mut self) {
-        if self.cursor_pos != 0 {
-            self.cursor_pos -= 1;
+        self.history.begin_group(self.cursor_pos);
+        let mut cursor = self.cursor_pos;
+        for (start,end) in ranges {
+            self.delete_range(start, end);
+            if cursor >= end {
+                cursor -= end - start;
+            }
+            else if cursor > start {
+                cursor = start;
+            }
         }
+        self.cursor_pos = cursor;
+        self.selection_anchor = None;
+        self.history.end_group();
     }
 
-    fn current_line_length(&mut self) -> usize {
-        let original_cursor_pos = self.cursor_pos;
+    // Line ending and encoding the document is saved with.
+    pub fn format_name(&self) -> String {
+        self.format.name()
+    }
 
-        self.move_to_last_line_end();
+    // Switches the line ending used on save, for every line of a mixed file too.
+    pub fn set_line_ending(&mut self,line_ending: LineEnding) {
+        self.format.line_ending = line_ending;
+        self.format.crlf_lines.clear();
+        self.format_changed();
+    }
 
-        let line_start = self.cursor_pos + 1;
+    // The file format isn't part of the undo history, so after changing it only a save clears the flag.
+    pub fn format_changed(&mut self) {
+        self.history.forget_saved();
+        self.modified = true;
+    }
 
+    pub fn insert(&mut self,cell: Cell) {
+        let c: char = cell.into();
+        self.history.record(Edit::Insert { pos: self.cursor_pos, text: c.to_string() }, self.cursor_pos);
+        let lines = self.text.len_lines();
+        self.text.insert_char(self.cursor_pos, c);
+        self.lines_changed(self.cursor_pos, lines);
         self.cursor_pos += 1;
+        self.modified = true;
+    }
 
-        self.move_to_next_line_end();
+    pub fn insert_str(&mut self,text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        self.history.record(Edit::Insert { pos: self.cursor_pos, text: text.to_string() }, self.cursor_pos);
+        let lines = self.text.len_lines();
+        self.text.insert(self.cursor_pos, text);
+        self.lines_changed(self.cursor_pos, lines);
+        self.cursor_pos += text.chars().count();
+        self.modified = true;
+    }
 
-        let line_length = self.cursor_pos - line_start;
+    pub fn delete(&mut self,index: usize) {
+        self.delete_range(index, index + 1);
+    }
 
-        self.cursor_pos = original_cursor_pos;
+    pub fn delete_range(&mut self,start: usize,end: usize) {
+        let deleted = self.text.slice(start..end).to_string();
+        self.history.record(Edit::Delete { pos: start, text: deleted }, self.cursor_pos);
+        let lines = self.text.len_lines();
+        self.text.remove(start..end);
+        self.lines_changed(start, lines);
+        self.modified = true;
+    }
 
-        line_length
+    // Lets the highlight cache know that an edit at `pos` took the document from `lines_before` lines
+    // to however many it has now.
+    fn lines_changed(&mut self,pos: usize,lines_before: usize) {
+        let line = self.text.char_to_line(pos);
+        self.highlights.edited(line, lines_before, self.text.len_lines());
+        self.format.lines_changed(line, lines_before, self.text.len_lines());
     }
 
-    fn move_to_last_line_end(&mut self) {
-        while self.cursor_pos != 0 {
-            if self.cursor_pos >= self.cells.len() {
-                break;
-            }
-            match &self.cells[self.cursor_pos] {
-                Cell::NewLine => return,
-                _ => {
-                    self.cursor_pos -= 1;
-                    continue;
-                }
-            }    
+    // Replaces [start, end) with `with` as one undo unit, leaving the cursor after it.
+    pub fn replace_range(&mut self,start: usize,end: usize,with: &str) {
+        self.history.begin_group(self.cursor_pos);
+        self.cursor_pos = start;
+        self.delete_range(start, end);
+        self.insert_str(with);
+        self.history.end_group();
+    }
+
+    // The selected range as (start, end), or None when nothing is selected.
+    pub fn selection(&self) -> Option<(usize,usize)> {
+        match self.selection_anchor {
+            Some(anchor) if anchor < self.cursor_pos => Some((anchor,self.cursor_pos)),
+            Some(anchor) if anchor > self.cursor_pos => Some((self.cursor_pos,anchor)),
+            _ => None
         }
     }
 
-    pub fn move_cursor_up(&mut self) {
-        let original_cursor_pos = self.cursor_pos;
-        if self.cursor_pos >= self.cells.len() {
-            if self.cursor_pos == 0 {
-                return;
-            }
-            self.cursor_pos -= 1;
+    pub fn selected_text(&self) -> Option<String> {
+        self.selection().map(|(start,end)| self.text.slice(start..end).to_string())
+    }
+
+    // Anchors a selection at the cursor unless one is already being extended.
+    pub fn start_selection(&mut self) {
+        if self.selection_anchor.is_none() {
+            self.selection_anchor = Some(self.cursor_pos);
         }
-        self.move_to_last_line_end();
-        let diff = original_cursor_pos - self.cursor_pos;
+    }
 
-        let line_cursor_pos = self.cursor_pos;
-        
-        if self.cursor_pos == 0 {
-            return;
+    pub fn clear_selection(&mut self) {
+        self.selection_anchor = None;
+    }
+
+    pub fn delete_selection(&mut self) -> bool {
+        if let Some((start,end)) = self.selection() {
+            self.history.seal();
+            self.delete_range(start, end);
+            self.history.seal();
+            self.cursor_pos = start;
+            self.selection_anchor = None;
+            true
+        }
+        else {
+            self.selection_anchor = None;
+            false
         }
-        self.cursor_pos -= 1;
-        self.move_to_last_line_end();
+    }
 
-        if line_cursor_pos - self.cursor_pos >= diff {
-            if diff == 0 {
-                self.cursor_pos += diff;
+    // Converts a screen column on a line into a char index, landing on the start of the grapheme cluster
+    // under it and clamping to the end of the line.
+    pub fn pos_at(&self,line: usize,column: usize) -> usize {
+        if line >= self.line_count() {
+            return self.len();
+        }
+        let mut x = 0;
+        let mut pos = self.line_start(line);
+        for grapheme in self.line_text(line).graphemes(true) {
+            x += unicode::width_at(grapheme, x, self.tab_width);
+            if x > column {
+                break;
             }
-            else {
-                self.cursor_pos += diff - 1;
+            pos += grapheme.chars().count();
+        }
+        pos
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.selection_anchor = None;
+        if let Some(unit) = self.history.pop_undo() {
+            for edit in unit.edits.iter().rev() {
+                let lines = self.text.len_lines();
+                edit.revert(&mut self.text);
+                self.lines_changed(edit.pos(), lines);
             }
+            self.cursor_pos = unit.cursor_before;
+            self.history.push_redo(unit);
+            self.modified = !self.history.is_saved();
+            true
         }
         else {
-            self.cursor_pos = line_cursor_pos;
+            false
         }
     }
 
-    fn move_to_next_line_end(&mut self) {
-        while self.cursor_pos + 1 < self.cells.len() {
-            match &self.cells[self.cursor_pos] {
-                Cell::NewLine => return,
-                _ => {
-                    self.cursor_pos += 1;
-                    continue;
-                }
+    pub fn redo(&mut self) -> bool {
+        self.selection_anchor = None;
+        if let Some(unit) = self.history.pop_redo() {
+            for edit in &unit.edits {
+                let lines = self.text.len_lines();
+                edit.apply(&mut self.text);
+                self.lines_changed(edit.pos(), lines);
             }
+            self.cursor_pos = unit.cursor_after();
+            self.history.push_undo(unit);
+            self.modified = !self.history.is_saved();
+            true
+        }
+        else {
+            false
         }
     }
 
-    pub fn move_cursor_down(&mut self) {
-        let initial_pos = self.cursor_pos;
-        self.move_to_last_line_end();
-        let diff = initial_pos - self.cursor_pos;
-
-        self.cursor_pos = initial_pos;
+    pub fn move_cursor_right(&mut self) {
+        self.history.seal();
+        self.cursor_pos = self.next_grapheme(self.cursor_pos);
+    }
 
-        self.move_to_next_line_end();
+    pub fn move_cursor_left(&mut self) {
+        self.history.seal();
+        self.cursor_pos = self.prev_grapheme(self.cursor_pos);
+    }
 
-        self.cursor_pos += 1;
+    pub fn move_cursor_up(&mut self) {
+        self.history.seal();
+        let line = self.current_line();
+        if line == 0 {
+            self.cursor_pos = 0;
+            return;
+        }
 
-        let len = self.current_line_length();
+        let column = self.current_column();
+        self.cursor_pos = self.pos_at(line - 1, column);
+    }
 
-        if diff < len {
-            self.cursor_pos += diff;
-        }
-        else {
-            self.cursor_pos += len;
+    pub fn move_cursor_down(&mut self) {
+        self.history.seal();
+        let line = self.current_line();
+        if line + 1 >= self.line_count() {
+            self.cursor_pos = self.len();
+            return;
         }
 
-        if self.cursor_pos > self.cells.len() {
-            self.cursor_pos = self.cells.len();
-        }
+        let column = self.current_column();
+        self.cursor_pos = self.pos_at(line + 1, column);
     }
-}
\ No newline at end of file
+}
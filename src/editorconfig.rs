@@ -0,0 +1,378 @@
+use regex::Regex;
+
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+
+use crate::format::{LineEnding,Encoding};
+use crate::settings::{Settings,IndentStyle};
+
+// The properties from `.editorconfig` files (https://editorconfig.org) that apply to one file.
+// Anything not set there is left to the editor's own settings.
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub struct EditorConfig {
+    pub indent_style: Option<IndentStyle>,
+    pub indent_size: Option<usize>,
+    pub tab_width: Option<usize>,
+    pub end_of_line: Option<LineEnding>,
+    // The encoding and whether it has a BOM.
+    pub charset: Option<(Encoding,bool)>,
+    pub trim_trailing_whitespace: bool,
+    pub insert_final_newline: Option<bool>
+}
+
+impl EditorConfig {
+    // Finds the `.editorconfig` files in the directories above `path`, up to one marked `root = true`,
+    // and works out the properties for it. Nearer files and later sections win.
+    pub fn resolve(path: &Path) -> Self {
+        let path = absolute(path);
+        let path_str = path.to_string_lossy().replace('\\', "/");
+
+        let mut files = Vec::new();
+        for dir in path.ancestors().skip(1) {
+            if let Ok(text) = std::fs::read_to_string(dir.join(".editorconfig")) {
+                let file = parse(&text);
+                let root = file.root;
+                files.push((dir.to_string_lossy().replace('\\', "/"),file));
+                if root {
+                    break;
+                }
+            }
+        }
+
+        let mut properties = HashMap::new();
+        for (dir,file) in files.iter().rev() {
+            for (glob,section) in &file.sections {
+                if glob_regex(dir, glob).is_some_and(|regex| regex.is_match(&path_str)) {
+                    for (key,value) in section {
+                        properties.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        // `unset` takes a property back out, as though no file had set it.
+        properties.retain(|_,value| value != "unset");
+
+        EditorConfig::from_properties(&properties)
+    }
+
+    fn from_properties(properties: &HashMap<String,String>) -> Self {
+        let get = |key: &str| properties.get(key).map(|value| value.as_str());
+        let number = |key: &str| get(key).and_then(|value| value.parse::<usize>().ok()).filter(|&n| n > 0);
+
+        let tab_width = number("tab_width");
+        let indent_size = if get("indent_size") == Some("tab") { tab_width } else { number("indent_size") };
+
+        Self {
+            indent_style: match get("indent_style") {
+                Some("space") => Some(IndentStyle::Spaces),
+                Some("tab") => Some(IndentStyle::Tabs),
+                _ => None
+            },
+            indent_size,
+            tab_width: tab_width.or(indent_size),
+            end_of_line: match get("end_of_line") {
+                Some("lf") => Some(LineEnding::Lf),
+                Some("crlf") => Some(LineEnding::CrLf),
+                _ => None
+            },
+            charset: match get("charset") {
+                Some("utf-8") => Some((Encoding::Utf8,false)),
+                Some("utf-8-bom") => Some((Encoding::Utf8,true)),
+                Some("utf-16le") => Some((Encoding::Utf16Le,true)),
+                Some("utf-16be") => Some((Encoding::Utf16Be,true)),
+                Some("latin1") => Some((Encoding::Latin1,false)),
+                _ => None
+            },
+            trim_trailing_whitespace: get("trim_trailing_whitespace") == Some("true"),
+            insert_final_newline: match get("insert_final_newline") {
+                Some("true") => Some(true),
+                Some("false") => Some(false),
+                _ => None
+            }
+        }
+    }
+
+    // What one press of Tab inserts, with the editor's settings filling in what the file doesn't say.
+    pub fn indent(&self,settings: &Settings) -> String {
+        match self.indent_style.unwrap_or(settings.indent_style) {
+            IndentStyle::Spaces => " ".repeat(self.indent_size.unwrap_or(settings.indent_width)),
+            IndentStyle::Tabs => "\t".to_string()
+        }
+    }
+
+    // How many columns apart tab stops are, falling back on the indent width.
+    pub fn tab_width(&self,settings: &Settings) -> usize {
+        self.tab_width.unwrap_or(settings.indent_width).max(1)
+    }
+}
+
+struct File {
+    root: bool,
+    // Each section's glob and its properties, in file order.
+    sections: Vec<(String,Vec<(String,String)>)>
+}
+
+fn parse(text: &str) -> File {
+    let mut file = File {
+        root: false,
+        sections: Vec::new()
+    };
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(glob) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            file.sections.push((glob.to_string(),Vec::new()));
+        }
+        else if let Some((key,value)) = line.split_once('=') {
+            let key = key.trim().to_ascii_lowercase();
+            let value = value.trim().to_ascii_lowercase();
+            match file.sections.last_mut() {
+                Some((_,properties)) => properties.push((key,value)),
+                // Properties before the first section describe the file itself.
+                None if key == "root" => file.root = value == "true",
+                None => {}
+            }
+        }
+    }
+
+    file
+}
+
+// Paths in the globs are relative to the directory of their file, so everything is matched on absolute paths.
+fn absolute(path: &Path) -> PathBuf {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    }
+    else {
+        std::env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(|_| path.to_path_buf())
+    };
+
+    // The file itself may not exist yet, but its directory usually does.
+    match (path.parent().and_then(|dir| std::fs::canonicalize(dir).ok()),path.file_name()) {
+        (Some(dir),Some(name)) => dir.join(name),
+        _ => path
+    }
+}
+
+// Turns a section glob from the `.editorconfig` in `dir` into a regex over absolute paths. A glob without
+// a '/' matches file names in any directory below, one with a '/' is anchored to `dir`.
+fn glob_regex(dir: &str,glob: &str) -> Option<Regex> {
+    let dir = dir.trim_end_matches('/');
+    let pattern = if glob.contains('/') {
+        format!("^{}/{}$",regex::escape(dir),translate(glob.trim_start_matches('/')))
+    }
+    else {
+        format!("^{}/(.*/)?{}$",regex::escape(dir),translate(glob))
+    };
+    Regex::new(&pattern).ok()
+}
+
+fn translate(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                regex.push_str(".*");
+                i += 1;
+            },
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                regex.push_str(&regex::escape(&chars[i].to_string()));
+            },
+            '[' => match chars[i..].iter().position(|&c| c == ']') {
+                Some(len) if len > 1 => {
+                    let class: String = chars[i + 1..i + len].iter().collect();
+                    let (negated,class) = match class.strip_prefix('!') {
+                        Some(class) => (true,class.to_string()),
+                        None => (false,class)
+                    };
+                    regex.push_str(if negated { "[^" } else { "[" });
+                    regex.push_str(&class.replace('\\', "\\\\").replace('[', "\\[").replace('^', "\\^"));
+                    regex.push(']');
+                    i += len;
+                },
+                _ => regex.push_str("\\[")
+            },
+            '{' => match closing_brace(&chars, i) {
+                Some(end) => {
+                    let inner: String = chars[i + 1..end].iter().collect();
+                    regex.push_str(&translate_braces(&inner));
+                    i = end;
+                },
+                None => regex.push_str("\\{")
+            },
+            c => regex.push_str(&regex::escape(&c.to_string()))
+        }
+        i += 1;
+    }
+    regex
+}
+
+fn closing_brace(chars: &[char],open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i,&c) in chars.iter().enumerate().skip(open) {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+// `{a,b,c}` matches any of the alternatives and `{1..10}` any number in the range. Anything else is literal.
+fn translate_braces(inner: &str) -> String {
+    if let Some((start,end)) = inner.split_once("..") {
+        if let (Ok(start),Ok(end)) = (start.parse::<i64>(),end.parse::<i64>()) {
+            let (start,end) = (start.min(end),start.max(end));
+            if end - start <= 1000 {
+                let numbers: Vec<String> = (start..=end).map(|n| n.to_string()).collect();
+                return format!("(?:{})",numbers.join("|"));
+            }
+            return "(?:-?[0-9]+)".to_string();
+        }
+    }
+
+    // Split on the commas that aren't inside a nested brace.
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut part = String::new();
+    for c in inner.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(std::mem::take(&mut part));
+                continue;
+            },
+            _ => {}
+        }
+        part.push(c);
+    }
+    parts.push(part);
+
+    if parts.len() == 1 {
+        return format!("\\{{{}\\}}",translate(inner));
+    }
+    format!("(?:{})",parts.iter().map(|part| translate(part)).collect::<Vec<_>>().join("|"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(glob: &str,path: &str) -> bool {
+        glob_regex("/project", glob).unwrap().is_match(path)
+    }
+
+    #[test]
+    fn star_stays_in_one_directory() {
+        assert!(matches("*.rs", "/project/main.rs"));
+        assert!(matches("*.rs", "/project/src/main.rs"));
+        assert!(!matches("*.rs", "/project/main.rsx"));
+        assert!(matches("src/*.rs", "/project/src/main.rs"));
+        assert!(!matches("src/*.rs", "/project/src/bin/main.rs"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        assert!(matches("src/**.rs", "/project/src/bin/main.rs"));
+        assert!(matches("**/test.txt", "/project/a/b/test.txt"));
+        assert!(!matches("src/**.rs", "/project/lib/main.rs"));
+    }
+
+    #[test]
+    fn unanchored_and_anchored() {
+        // Without a '/' the glob matches the file name anywhere below the directory.
+        assert!(matches("Makefile", "/project/sub/Makefile"));
+        // With one it's relative to the directory, a leading '/' included.
+        assert!(matches("/Makefile", "/project/Makefile"));
+        assert!(!matches("/Makefile", "/project/sub/Makefile"));
+        assert!(matches("sub/Makefile", "/project/sub/Makefile"));
+        assert!(!matches("sub/Makefile", "/project/other/sub/Makefile"));
+        assert!(!matches("*.rs", "/elsewhere/main.rs"));
+    }
+
+    #[test]
+    fn braces() {
+        assert!(matches("*.{js,ts}", "/project/a.js"));
+        assert!(matches("*.{js,ts}", "/project/a.ts"));
+        assert!(!matches("*.{js,ts}", "/project/a.rs"));
+        assert!(matches("file{1..3}.txt", "/project/file2.txt"));
+        assert!(!matches("file{1..3}.txt", "/project/file4.txt"));
+        assert!(!matches("file{1..3}.txt", "/project/file12.txt"));
+        assert!(matches("{single}", "/project/{single}"));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(matches("[ab].txt", "/project/a.txt"));
+        assert!(!matches("[ab].txt", "/project/c.txt"));
+        assert!(matches("[!x].txt", "/project/y.txt"));
+        assert!(!matches("[!x].txt", "/project/x.txt"));
+        assert!(matches("?.txt", "/project/z.txt"));
+        assert!(!matches("?.txt", "/project/zz.txt"));
+    }
+
+    // Makes a fresh directory tree for one test, with the given `.editorconfig` files in it.
+    fn tree(name: &str,files: &[(&str,&str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vce-editorconfig-{}-{}",name,std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (path,text) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn nearer_files_win_and_root_stops_the_search() {
+        let dir = tree("root", &[
+            (".editorconfig", "root = true\n[*]\nindent_size = 8\nend_of_line = crlf\n"),
+            ("a/.editorconfig", "[*.rs]\nindent_size = 2\n"),
+            ("a/b/.editorconfig", "root = true\n[*.txt]\nindent_style = tab\n")
+        ]);
+
+        let config = EditorConfig::resolve(&dir.join("a/main.rs"));
+        assert_eq!(config.indent_size, Some(2));
+        assert_eq!(config.end_of_line, Some(LineEnding::CrLf));
+
+        let config = EditorConfig::resolve(&dir.join("a/b/notes.txt"));
+        assert_eq!(config.indent_style, Some(IndentStyle::Tabs));
+        assert_eq!(config.indent_size, None);
+        assert_eq!(config.end_of_line, None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unset_takes_a_property_back_out() {
+        let dir = tree("unset", &[
+            (".editorconfig", "root = true\n[*]\ntab_width = 4\ninsert_final_newline = true\n"),
+            ("sub/.editorconfig", "[*]\ntab_width = unset\n")
+        ]);
+
+        let config = EditorConfig::resolve(&dir.join("sub/file.txt"));
+        assert_eq!(config.tab_width, None);
+        assert_eq!(config.insert_final_newline, Some(true));
+
+        let config = EditorConfig::resolve(&dir.join("file.txt"));
+        assert_eq!(config.tab_width, Some(4));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,37 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum EditorError {
+    // Reading or writing `path` failed.
+    Io { path: String, source: io::Error },
+    // The configuration couldn't be found or loaded.
+    Config(String)
+}
+
+impl EditorError {
+    pub fn io(path: &str,source: io::Error) -> Self {
+        EditorError::Io {
+            path: path.to_string(),
+            source
+        }
+    }
+}
+
+impl fmt::Display for EditorError {
+    fn fmt(&self,f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditorError::Io { path, source } => write!(f, "{}: {}", path, source),
+            EditorError::Config(msg) => write!(f, "Config error: {}", msg)
+        }
+    }
+}
+
+impl std::error::Error for EditorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EditorError::Io { source, .. } => Some(source),
+            EditorError::Config(_) => None
+        }
+    }
+}
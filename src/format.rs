@@ -0,0 +1,425 @@
+use std::io::{self,Write};
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    // Both kinds in one file. The buffer only has '\n's; which lines had CRLF is kept in `FileFormat`.
+    Mixed
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1
+}
+
+impl LineEnding {
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+            LineEnding::Mixed => "Mixed"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "lf" => Some(LineEnding::Lf),
+            "crlf" => Some(LineEnding::CrLf),
+            _ => None
+        }
+    }
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "Latin-1"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().replace('_', "-").as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-16le" | "utf16le" => Some(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" => Some(Encoding::Utf16Be),
+            "latin-1" | "latin1" | "iso-8859-1" => Some(Encoding::Latin1),
+            _ => None
+        }
+    }
+}
+
+// How a document is laid out on disk, remembered on load so saving writes it back the same way.
+#[derive(Debug,Clone,PartialEq)]
+pub struct FileFormat {
+    pub encoding: Encoding,
+    pub bom: bool,
+    pub line_ending: LineEnding,
+    // Whether the file ended with a line break. It's kept out of the buffer and added back on save.
+    pub final_newline: bool,
+    // For mixed files, whether each line ended in CRLF, kept in step with edits by `lines_changed`.
+    pub crlf_lines: Vec<bool>
+}
+
+impl Default for FileFormat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileFormat {
+    pub fn new() -> Self {
+        Self {
+            encoding: Encoding::Utf8,
+            bom: false,
+            line_ending: LineEnding::Lf,
+            final_newline: false,
+            crlf_lines: Vec::new()
+        }
+    }
+
+    // The line break written after `line`.
+    fn newline(&self,line: usize) -> &'static str {
+        match self.line_ending {
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Mixed if self.crlf_lines.get(line) == Some(&true) => "\r\n",
+            _ => "\n"
+        }
+    }
+
+    // Follows an edit at `line` that took the buffer from `lines_before` lines to `lines_after`. New lines
+    // take the ending of the line they were split from; joined lines keep the ending of the last one.
+    pub fn lines_changed(&mut self,line: usize,lines_before: usize,lines_after: usize) {
+        if self.line_ending != LineEnding::Mixed || line >= self.crlf_lines.len() {
+            return;
+        }
+
+        if lines_after > lines_before {
+            let crlf = self.crlf_lines[line];
+            self.crlf_lines.splice(line..line, std::iter::repeat_n(crlf, lines_after - lines_before));
+        }
+        else {
+            let removed = (line + lines_before - lines_after).min(self.crlf_lines.len());
+            self.crlf_lines.drain(line..removed);
+        }
+    }
+
+    pub fn name(&self) -> String {
+        format!("{} | {}{}",self.line_ending.name(),self.encoding.name(),if self.bom { " BOM" } else { "" })
+    }
+}
+
+// Decodes a file's bytes into buffer text with '\n' line breaks, along with the format it was in.
+pub fn decode(bytes: &[u8]) -> (String,FileFormat) {
+    let mut format = FileFormat::new();
+
+    // Anything that doesn't decode cleanly is read as Latin-1, which maps every byte to a char and back,
+    // so saving never changes bytes the buffer couldn't show.
+    let decoded = if let Some(rest) = bytes.strip_prefix(&[0xEF,0xBB,0xBF]) {
+        std::str::from_utf8(rest).ok().map(|text| (text.to_string(),Encoding::Utf8,true))
+    }
+    else if let Some(rest) = bytes.strip_prefix(&[0xFF,0xFE]) {
+        decode_utf16(rest, false).map(|text| (text,Encoding::Utf16Le,true))
+    }
+    else if let Some(rest) = bytes.strip_prefix(&[0xFE,0xFF]) {
+        decode_utf16(rest, true).map(|text| (text,Encoding::Utf16Be,true))
+    }
+    else if let Ok(text) = std::str::from_utf8(bytes) {
+        Some((text.to_string(),Encoding::Utf8,false))
+    }
+    else if let Some(big_endian) = guess_utf16(bytes) {
+        let encoding = if big_endian { Encoding::Utf16Be } else { Encoding::Utf16Le };
+        decode_utf16(bytes, big_endian).map(|text| (text,encoding,false))
+    }
+    else {
+        None
+    };
+
+    let mut text = match decoded {
+        Some((text,encoding,bom)) => {
+            format.encoding = encoding;
+            format.bom = bom;
+            text
+        },
+        None => {
+            format.encoding = Encoding::Latin1;
+            bytes.iter().map(|&b| b as char).collect()
+        }
+    };
+
+    let crlf = text.matches("\r\n").count();
+    let lf = text.matches('\n').count() - crlf;
+    format.line_ending = match (crlf,lf) {
+        (0,_) => LineEnding::Lf,
+        (_,0) => LineEnding::CrLf,
+        _ => LineEnding::Mixed
+    };
+
+    if format.line_ending == LineEnding::Mixed {
+        format.crlf_lines = text.split('\n').map(|line| line.ends_with('\r')).collect();
+    }
+    if format.line_ending != LineEnding::Lf {
+        text = text.replace("\r\n", "\n");
+    }
+
+    if text.ends_with('\n') {
+        text.pop();
+        format.crlf_lines.pop();
+        format.final_newline = true;
+    }
+
+    (text,format)
+}
+
+// None for an odd number of bytes or an unpaired surrogate.
+fn decode_utf16(bytes: &[u8],big_endian: bool) -> Option<String> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let units = bytes.chunks_exact(2).map(|pair| {
+        if big_endian {
+            u16::from_be_bytes([pair[0], pair[1]])
+        }
+        else {
+            u16::from_le_bytes([pair[0], pair[1]])
+        }
+    });
+    std::char::decode_utf16(units).collect::<Result<String,_>>().ok()
+}
+
+// Spots BOM-less UTF-16 by the zero high bytes that mostly-ASCII text leaves in every other byte.
+fn guess_utf16(bytes: &[u8]) -> Option<bool> {
+    if bytes.len() < 2 || !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let pairs = bytes.len() / 2;
+    let even_zeros = bytes.iter().step_by(2).filter(|&&b| b == 0).count();
+    let odd_zeros = bytes.iter().skip(1).step_by(2).filter(|&&b| b == 0).count();
+
+    if even_zeros * 2 > pairs && odd_zeros == 0 {
+        Some(true)
+    }
+    else if odd_zeros * 2 > pairs && even_zeros == 0 {
+        Some(false)
+    }
+    else {
+        None
+    }
+}
+
+// Writes buffer text back out in `format`, turning '\n' into the file's line ending.
+pub fn encode<'a,W,I>(writer: &mut W,chunks: I,format: &FileFormat) -> io::Result<()>
+    where W: Write, I: Iterator<Item = &'a str>
+{
+    if format.bom {
+        match format.encoding {
+            Encoding::Utf8 => writer.write_all(&[0xEF,0xBB,0xBF])?,
+            Encoding::Utf16Le => writer.write_all(&[0xFF,0xFE])?,
+            Encoding::Utf16Be => writer.write_all(&[0xFE,0xFF])?,
+            Encoding::Latin1 => {}
+        }
+    }
+
+    // The line the next '\n' ends, for looking up a mixed file's line endings.
+    let mut line = 0;
+    for chunk in chunks {
+        match format.line_ending {
+            LineEnding::Lf => write_encoded(writer, chunk, format.encoding)?,
+            LineEnding::CrLf => write_encoded(writer, &chunk.replace('\n', "\r\n"), format.encoding)?,
+            LineEnding::Mixed => {
+                let mut rest = chunk;
+                while let Some(end) = rest.find('\n') {
+                    write_encoded(writer, &rest[..end], format.encoding)?;
+                    write_encoded(writer, format.newline(line), format.encoding)?;
+                    line += 1;
+                    rest = &rest[end + 1..];
+                }
+                write_encoded(writer, rest, format.encoding)?;
+            }
+        }
+    }
+
+    if format.final_newline {
+        write_encoded(writer, format.newline(line), format.encoding)?;
+    }
+
+    Ok(())
+}
+
+fn write_encoded<W>(writer: &mut W,text: &str,encoding: Encoding) -> io::Result<()>
+    where W: Write
+{
+    match encoding {
+        Encoding::Utf8 => writer.write_all(text.as_bytes()),
+        Encoding::Utf16Le => {
+            for unit in text.encode_utf16() {
+                writer.write_all(&unit.to_le_bytes())?;
+            }
+            Ok(())
+        },
+        Encoding::Utf16Be => {
+            for unit in text.encode_utf16() {
+                writer.write_all(&unit.to_be_bytes())?;
+            }
+            Ok(())
+        },
+        Encoding::Latin1 => {
+            let mut bytes = Vec::with_capacity(text.len());
+            for c in text.chars() {
+                if (c as u32) > 0xFF {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("'{}' can't be saved as Latin-1",c)));
+                }
+                bytes.push(c as u8);
+            }
+            writer.write_all(&bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(text: &str,format: &FileFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        encode(&mut bytes, std::iter::once(text), format).unwrap();
+        bytes
+    }
+
+    // Decodes `bytes`, checks the buffer text, and that saving it unchanged gives the same bytes back.
+    fn round_trip(bytes: &[u8],text: &str) -> FileFormat {
+        let (decoded,format) = decode(bytes);
+        assert_eq!(decoded, text);
+        assert_eq!(encoded(&decoded, &format), bytes);
+        format
+    }
+
+    fn utf16(text: &str,big_endian: bool) -> Vec<u8> {
+        text.encode_utf16().flat_map(|unit| if big_endian { unit.to_be_bytes() } else { unit.to_le_bytes() }).collect()
+    }
+
+    #[test]
+    fn utf8() {
+        let format = round_trip("héllo\nworld\n".as_bytes(), "héllo\nworld");
+        assert_eq!(format.encoding, Encoding::Utf8);
+        assert!(!format.bom);
+        assert_eq!(format.line_ending, LineEnding::Lf);
+        assert!(format.final_newline);
+    }
+
+    #[test]
+    fn no_final_newline() {
+        let format = round_trip(b"a\nb", "a\nb");
+        assert!(!format.final_newline);
+    }
+
+    #[test]
+    fn utf8_bom() {
+        let format = round_trip(b"\xEF\xBB\xBFbom\n", "bom");
+        assert_eq!(format.encoding, Encoding::Utf8);
+        assert!(format.bom);
+    }
+
+    #[test]
+    fn utf16_bom() {
+        let mut le = vec![0xFF,0xFE];
+        le.extend(utf16("a\u{1F600}\n", false));
+        let format = round_trip(&le, "a\u{1F600}");
+        assert_eq!(format.encoding, Encoding::Utf16Le);
+        assert!(format.bom);
+
+        let mut be = vec![0xFE,0xFF];
+        be.extend(utf16("a\u{1F600}\n", true));
+        let format = round_trip(&be, "a\u{1F600}");
+        assert_eq!(format.encoding, Encoding::Utf16Be);
+        assert!(format.bom);
+    }
+
+    #[test]
+    fn utf16_without_bom() {
+        let format = round_trip(&utf16("café au lait\n", false), "café au lait");
+        assert_eq!(format.encoding, Encoding::Utf16Le);
+        assert!(!format.bom);
+
+        let format = round_trip(&utf16("café au lait\n", true), "café au lait");
+        assert_eq!(format.encoding, Encoding::Utf16Be);
+    }
+
+    #[test]
+    fn latin1() {
+        let format = round_trip(b"caf\xE9\n", "café");
+        assert_eq!(format.encoding, Encoding::Latin1);
+    }
+
+    #[test]
+    fn bad_utf8_after_bom_is_latin1() {
+        let format = round_trip(b"\xEF\xBB\xBFcaf\xE9", "\u{EF}\u{BB}\u{BF}caf\u{E9}");
+        assert_eq!(format.encoding, Encoding::Latin1);
+        assert!(!format.bom);
+    }
+
+    #[test]
+    fn unpaired_surrogate_is_latin1() {
+        let bytes = [0xFF,0xFE,b'a',0x00,0x00,0xD8,b'b',0x00];
+        let format = round_trip(&bytes, "\u{FF}\u{FE}a\u{0}\u{0}\u{D8}b\u{0}");
+        assert_eq!(format.encoding, Encoding::Latin1);
+    }
+
+    #[test]
+    fn odd_length_utf16_is_latin1() {
+        let (_,format) = decode(&[0xFF,0xFE,b'a',0x00,b'b']);
+        assert_eq!(format.encoding, Encoding::Latin1);
+    }
+
+    #[test]
+    fn crlf() {
+        let format = round_trip(b"one\r\ntwo\r\n", "one\ntwo");
+        assert_eq!(format.line_ending, LineEnding::CrLf);
+        assert!(format.final_newline);
+    }
+
+    #[test]
+    fn mixed() {
+        let format = round_trip(b"one\r\ntwo\nthree\r\n", "one\ntwo\nthree");
+        assert_eq!(format.line_ending, LineEnding::Mixed);
+        assert_eq!(format.crlf_lines, vec![true,false,true]);
+
+        round_trip(b"one\ntwo\r\nthree", "one\ntwo\nthree");
+    }
+
+    #[test]
+    fn mixed_lines_follow_edits() {
+        let (_,mut format) = decode(b"one\r\ntwo\nthree\r\n");
+
+        // Splitting "two" in half gives both halves its LF.
+        format.lines_changed(1, 3, 4);
+        assert_eq!(encoded("one\ntw\no\nthree", &format), b"one\r\ntw\no\nthree\r\n");
+
+        // Joining "one" onto "tw" keeps the ending of "tw".
+        format.lines_changed(0, 4, 3);
+        assert_eq!(encoded("onetw\no\nthree", &format), b"onetw\no\nthree\r\n");
+    }
+
+    #[test]
+    fn chunks_share_line_numbers() {
+        let (_,format) = decode(b"a\r\nb\nc\r\n");
+        let mut bytes = Vec::new();
+        encode(&mut bytes, vec!["a\nb","\nc"].into_iter(), &format).unwrap();
+        assert_eq!(bytes, b"a\r\nb\nc\r\n");
+    }
+
+    #[test]
+    fn latin1_refuses_wide_chars() {
+        let mut format = FileFormat::new();
+        format.encoding = Encoding::Latin1;
+        let mut bytes = Vec::new();
+        assert!(encode(&mut bytes, std::iter::once("\u{20AC}"), &format).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use ropey::Rope;
+
+use std::time::{Duration,Instant};
+
+// Edits closer together than this can be merged into one undo unit.
+const GROUP_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Edit {
+    Insert { pos: usize, text: String },
+    Delete { pos: usize, text: String }
+}
+
+impl Edit {
+    pub fn apply(&self,text: &mut Rope) {
+        match self {
+            Edit::Insert { pos, text: inserted } => text.insert(*pos, inserted),
+            Edit::Delete { pos, text: deleted } => text.remove(*pos..*pos + deleted.chars().count())
+        }
+    }
+
+    pub fn revert(&self,text: &mut Rope) {
+        match self {
+            Edit::Insert { pos, text: inserted } => text.remove(*pos..*pos + inserted.chars().count()),
+            Edit::Delete { pos, text: deleted } => text.insert(*pos, deleted)
+        }
+    }
+
+    pub fn pos(&self) -> usize {
+        match self {
+            Edit::Insert { pos, .. } | Edit::Delete { pos, .. } => *pos
+        }
+    }
+
+    // Where the cursor ends up after this edit has been applied.
+    pub fn end_pos(&self) -> usize {
+        match self {
+            Edit::Insert { pos, text } => pos + text.chars().count(),
+            Edit::Delete { pos, .. } => *pos
+        }
+    }
+
+    // Tries to fold `next` into this edit, so a burst of typing or deleting stays a single edit.
+    fn merge(&mut self,next: &Edit) -> bool {
+        match (self, next) {
+            (Edit::Insert { pos, text }, Edit::Insert { pos: next_pos, text: next_text }) => {
+                if *pos + text.chars().count() == *next_pos && !text.ends_with('\n') {
+                    text.push_str(next_text);
+                    return true;
+                }
+                false
+            },
+            (Edit::Delete { pos, text }, Edit::Delete { pos: next_pos, text: next_text }) => {
+                if *next_pos == *pos {
+                    text.push_str(next_text);
+                    return true;
+                }
+                if *next_pos + next_text.chars().count() == *pos {
+                    text.insert_str(0, next_text);
+                    *pos = *next_pos;
+                    return true;
+                }
+                false
+            },
+            _ => false
+        }
+    }
+}
+
+pub struct UndoUnit {
+    pub edits: Vec<Edit>,
+    pub cursor_before: usize
+}
+
+impl UndoUnit {
+    pub fn cursor_after(&self) -> usize {
+        self.edits.last().map(Edit::end_pos).unwrap_or(self.cursor_before)
+    }
+}
+
+pub struct History {
+    undo_stack: Vec<UndoUnit>,
+    redo_stack: Vec<UndoUnit>,
+    last_edit: Option<Instant>,
+    sealed: bool,
+    group_depth: usize,
+    // How many units the undo stack held when the document was last saved, or None once that state
+    // can't be reached by undoing and redoing any more.
+    saved: Option<usize>
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            last_edit: None,
+            sealed: true,
+            group_depth: 0,
+            saved: Some(0)
+        }
+    }
+
+    pub fn record(&mut self,edit: Edit,cursor_before: usize) {
+        self.clear_redo();
+
+        let recent = self.last_edit.map(|t| t.elapsed() < GROUP_TIMEOUT).unwrap_or(false);
+        self.last_edit = Some(Instant::now());
+
+        if self.group_depth > 0 {
+            if let Some(unit) = self.undo_stack.last_mut() {
+                unit.edits.push(edit);
+                return;
+            }
+        }
+
+        if !self.sealed && recent {
+            if let Some(last) = self.undo_stack.last_mut().and_then(|unit| unit.edits.last_mut()) {
+                if last.merge(&edit) {
+                    return;
+                }
+            }
+        }
+
+        self.undo_stack.push(UndoUnit {
+            edits: vec![edit],
+            cursor_before
+        });
+        self.sealed = false;
+    }
+
+    // Stops the next edit from being merged into the current undo unit.
+    pub fn seal(&mut self) {
+        self.sealed = true;
+    }
+
+    // Everything recorded until the matching end_group becomes a single undo unit.
+    pub fn begin_group(&mut self,cursor_before: usize) {
+        if self.group_depth == 0 {
+            self.clear_redo();
+            self.undo_stack.push(UndoUnit {
+                edits: Vec::new(),
+                cursor_before
+            });
+        }
+        self.group_depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        if self.group_depth == 0 {
+            return;
+        }
+
+        self.group_depth -= 1;
+        if self.group_depth == 0 {
+            if self.undo_stack.last().map(|unit| unit.edits.is_empty()).unwrap_or(false) {
+                self.undo_stack.pop();
+            }
+            self.sealed = true;
+        }
+    }
+
+    // Marks the current state as the one on disk. Sealing keeps later typing out of the saved unit.
+    pub fn mark_saved(&mut self) {
+        self.sealed = true;
+        self.saved = Some(self.undo_stack.len());
+    }
+
+    // Forgets the saved state, for changes the history doesn't track such as the file format.
+    pub fn forget_saved(&mut self) {
+        self.saved = None;
+    }
+
+    // Whether undoing and redoing has landed back on the saved state.
+    pub fn is_saved(&self) -> bool {
+        self.saved == Some(self.undo_stack.len())
+    }
+
+    // A new edit drops the redo stack, and the saved state with it if it was in there.
+    fn clear_redo(&mut self) {
+        if self.saved.map(|saved| saved > self.undo_stack.len()).unwrap_or(false) {
+            self.saved = None;
+        }
+        self.redo_stack.clear();
+    }
+
+    pub fn pop_undo(&mut self) -> Option<UndoUnit> {
+        self.sealed = true;
+        self.undo_stack.pop()
+    }
+
+    pub fn pop_redo(&mut self) -> Option<UndoUnit> {
+        self.sealed = true;
+        self.redo_stack.pop()
+    }
+
+    pub fn push_undo(&mut self,unit: UndoUnit) {
+        self.undo_stack.push(unit);
+    }
+
+    pub fn push_redo(&mut self,unit: UndoUnit) {
+        self.redo_stack.push(unit);
+    }
+}
@@ -1,81 +1,720 @@
 use regex::Regex;
 use crossterm::style::Color;
-use crate::Drawable;
+use ropey::Rope;
 
 use std::str::FromStr;
 
 use std::collections::HashMap;
 
+// How a piece of text should look: a fixed color, or a scope name like `keyword` or `comment.block`
+// that the theme gives a style to.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Scope {
+    Rgb(Color),
+    // An index into `SyntaxHighlighter::scopes`.
+    Named(usize)
+}
+
+// A run of highlighted text, as char offsets within its line.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub scope: Scope
+}
+
+enum Rule {
+    // Colors the `color` group of each match, or the whole match if there's no such group.
+    // Pushes and pops can't start inside one of its matches, which is how escapes keep a string open.
+    Color { regex: Regex, scope: Scope },
+    // Enters a context. The opening match gets the context's scope.
+    Push { regex: Regex, context: usize },
+    // Leaves the current context. The closing match still gets its scope.
+    Pop { regex: Regex }
+}
+
+// A set of rules that applies while the highlighter is inside something like a string or a comment.
+pub struct Context {
+    pub name: String,
+    // Scope of the text in the context that no rule matches.
+    pub scope: Option<Scope>,
+    rules: Vec<Rule>
+}
+
+// The grammar for one file type. The first context is the top level of the file.
+pub struct Syntax {
+    pub contexts: Vec<Context>
+}
+
+// Rules as written in the config, before includes and context names are resolved.
+enum RawRule {
+    Color(Regex,Scope),
+    Push(String,Regex),
+    Pop(Regex),
+    Include(String)
+}
+
+struct RawContext {
+    name: String,
+    scope: Option<Scope>,
+    rules: Vec<RawRule>
+}
+
+impl RawContext {
+    fn new(name: String,scope: Option<Scope>) -> Self {
+        Self {
+            name,
+            scope,
+            rules: Vec::new()
+        }
+    }
+}
+
+// Where a rule matched in a line: the whole match, then its `color` group (the whole match again if
+// there's no such group), as byte offsets.
+type Found = (usize,usize,usize,usize);
+
+// The searches made while highlighting one line. Every push and pop searches the rules again, but a
+// match found from an earlier position is still the first one from any later position up to its start,
+// so most of those searches don't have to run again.
+struct Searches<'a> {
+    line: &'a str,
+    // One slot per rule index, shared by the contexts: whose rule it holds, where its last search
+    // started and what it found.
+    found: Vec<Option<(usize,usize,Option<Found>)>>
+}
+
+impl<'a> Searches<'a> {
+    fn new(line: &'a str) -> Self {
+        Self {
+            line,
+            found: Vec::new()
+        }
+    }
+
+    // The first match of the rule's regex starting at or after `at`.
+    fn find(&mut self,rule: (usize,usize),regex: &Regex,at: usize) -> Option<Found> {
+        let (context,index) = rule;
+        if let Some(Some((cached_context,from,found))) = self.found.get(index).copied() {
+            if cached_context == context && from <= at && found.is_none_or(|(start,_,_,_)| start >= at) {
+                return found;
+            }
+        }
+
+        let found = match regex.capture_names().position(|name| name == Some("color")) {
+            Some(group) => {
+                let mut locations = regex.capture_locations();
+                regex.captures_read_at(&mut locations, self.line, at).map(|whole| {
+                    let (start,end) = locations.get(group).unwrap_or((whole.start(),whole.end()));
+                    (whole.start(),whole.end(),start,end)
+                })
+            },
+            None => regex.find_at(self.line, at).map(|m| (m.start(),m.end(),m.start(),m.end()))
+        };
+        if self.found.len() <= index {
+            self.found.resize(index + 1, None);
+        }
+        self.found[index] = Some((context,at,found));
+        found
+    }
+}
+
+// The color rules' matches in a context from some position on, found only as far into the line as
+// they're needed.
+struct Colored {
+    // Where each rule's next search starts.
+    next: Vec<usize>,
+    // (rule, match, scope) for every non-empty match found so far.
+    found: Vec<(usize,Found,Scope)>
+}
+
+impl Colored {
+    fn new(context: &Context,pos: usize) -> Self {
+        Self {
+            next: vec![pos; context.rules.len()],
+            found: Vec::new()
+        }
+    }
+
+    // Finds every match that starts before `limit`.
+    fn extend(&mut self,searches: &mut Searches,context_id: usize,context: &Context,limit: usize) {
+        for (i,rule) in context.rules.iter().enumerate() {
+            let (regex,scope) = match rule {
+                Rule::Color { regex, scope } => (regex,*scope),
+                _ => continue
+            };
+            while self.next[i] < limit {
+                match searches.find((context_id,i), regex, self.next[i]) {
+                    Some(found) if found.0 < limit => {
+                        if found.0 < found.1 {
+                            self.found.push((i,found,scope));
+                            self.next[i] = found.1;
+                        }
+                        else {
+                            // Step over an empty match; past the end of the line there's nothing left.
+                            let line = searches.line;
+                            self.next[i] = line[found.0..].chars().next().map_or(line.len() + 1, |c| found.0 + c.len_utf8());
+                        }
+                    },
+                    _ => break
+                }
+            }
+        }
+    }
+
+    // Whether `at` falls inside a match found so far.
+    fn masks(&self,at: usize) -> bool {
+        self.found.iter().any(|&(_,(start,end,_,_),_)| start < at && at < end)
+    }
+
+    // The matches rule by rule, in the order the rules are written, so later rules draw over earlier ones.
+    fn in_rule_order(&mut self) -> impl Iterator<Item = (usize,Found,Scope)> + '_ {
+        self.found.sort_by_key(|&(rule,_,_)| rule);
+        self.found.iter().copied()
+    }
+}
+
+// Contexts can't nest deeper than this, so runaway pushes can't grow the state without bound.
+const MAX_DEPTH: usize = 64;
+
+// What's still open at the end of a line and carries on into the next one.
+#[derive(Debug,Clone,PartialEq,Eq,Hash,Default)]
+pub struct LineState {
+    // The contexts entered and not yet left, innermost last, as indices into `Syntax::contexts`.
+    pub stack: Vec<usize>
+}
+
+impl LineState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn context(&self) -> usize {
+        self.stack.last().copied().unwrap_or(0)
+    }
+}
+
+// States are stored as indices into `HighlightCache::states`.
+#[derive(Clone)]
+struct CachedLine {
+    start: usize,
+    end: usize,
+    spans: Vec<Span>
+}
+
+// A document's highlighting, kept line by line so an edit only re-highlights lines whose text or
+// starting state changed.
+pub struct HighlightCache {
+    // One entry per line of the document. None for lines edited since they were highlighted.
+    lines: Vec<Option<CachedLine>>,
+    // Every line before this one is up to date.
+    valid_to: usize,
+    file_type: Option<String>,
+    // Every distinct state seen so far, so lines can refer to them by index and be compared cheaply.
+    // The first one is the top level.
+    states: Vec<LineState>,
+    state_ids: HashMap<LineState,usize>
+}
+
+impl Default for HighlightCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HighlightCache {
+    pub fn new() -> Self {
+        let mut cache = Self {
+            lines: Vec::new(),
+            valid_to: 0,
+            file_type: None,
+            states: Vec::new(),
+            state_ids: HashMap::new()
+        };
+        cache.clear();
+        cache
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.valid_to = 0;
+        self.states.clear();
+        self.state_ids.clear();
+        self.intern(LineState::new());
+    }
+
+    fn intern(&mut self,state: LineState) -> usize {
+        if let Some(&id) = self.state_ids.get(&state) {
+            return id;
+        }
+        self.states.push(state.clone());
+        self.state_ids.insert(state, self.states.len() - 1);
+        self.states.len() - 1
+    }
+
+    // An edit changed `line` and turned the document's `lines_before` lines into `lines_after`,
+    // by adding or removing lines right after it.
+    pub fn edited(&mut self,line: usize,lines_before: usize,lines_after: usize) {
+        self.valid_to = self.valid_to.min(line);
+        if line >= self.lines.len() {
+            return;
+        }
+
+        if lines_after > lines_before {
+            let added = std::iter::repeat_n(None, lines_after - lines_before);
+            self.lines.splice(line + 1..line + 1, added);
+        }
+        else {
+            let removed = (line + 1 + lines_before - lines_after).min(self.lines.len());
+            self.lines.drain(line + 1..removed);
+        }
+        self.lines[line] = None;
+    }
+
+    pub fn spans(&self,line: usize) -> &[Span] {
+        match self.lines.get(line) {
+            Some(Some(cached)) => &cached.spans,
+            _ => &[]
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct SyntaxHighlighter {
-    buffer: String,
-    syntax_coloring: HashMap<String,Vec<(Regex,Color)>>
+    syntax_coloring: HashMap<String,Syntax>,
+    // Names of the scopes used by the grammars.
+    scopes: Vec<String>
 }
 
 impl SyntaxHighlighter {
-    pub fn new(config: String) -> Self {
+    // Besides the flat `color <scope> <regex>` lines, the config can describe contexts:
+    //
+    //   context <name> [<scope>]      starts a context; the rules after it belong to it until `end`
+    //   push <name> <regex>           enters the named context where the regex matches
+    //   pop <regex>                   leaves the current context where the regex matches
+    //   include <name>                uses the rules of another context here as well
+    //   region <scope> <start> <end>  shorthand for a context entered at start and left at end
+    //
+    // A scope is either a name the theme styles, like `keyword` or `string.escape`, or a fixed `rgb(r,g,b)`.
+    // Rules outside any context block belong to the top level, named `main`. `file <ext>` ends a grammar.
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        let color_reg = regex::Regex::from_str(r#"color rgb\((?P<r>[0-9]+)( )*,(?P<g>[0-9]+)( )*,(?P<b>[0-9]+)( )*\) (?P<regex>.*$)"#).unwrap();
+    // Adds the grammars in `config` on top of those already loaded, replacing any for the same file type.
+    // Returns the problems found, each prefixed with `source` and the line number.
+    pub fn load(&mut self,source: &str,config: &str) -> Vec<String> {
 
-        let mut syntax_coloring = HashMap::new();
+        let scope = r#"(?P<scope>rgb\((?P<r>[0-9]+)( )*,(?P<g>[0-9]+)( )*,(?P<b>[0-9]+)( )*\)|[A-Za-z][\w.-]*)"#;
+        let color_reg = regex::Regex::from_str(&format!(r#"color {} (?P<regex>.*$)"#,scope)).unwrap();
+        let region_reg = regex::Regex::from_str(&format!(r#"region {} (?P<start>\S+) (?P<end>\S+)$"#,scope)).unwrap();
+        let context_reg = regex::Regex::from_str(&format!(r#"context (?P<name>\S+)( {})?$"#,scope)).unwrap();
+
+        let syntax_coloring = &mut self.syntax_coloring;
+        let scopes = &mut self.scopes;
+        let mut errors = Vec::new();
+
+        let mut contexts = vec![RawContext::new("main".to_string(), None)];
+        let mut current = 0;
+        for (num,line) in config.lines().enumerate() {
+            let mut compile = |regex: &str| SyntaxHighlighter::compile(regex).map_err(|err| errors.push(format!("{}:{}: {}",source,num + 1,err))).ok();
+            // Indentation is allowed, but trailing spaces may be part of a regex.
+            let line = line.trim_start();
 
-        let mut colors = Vec::new();
-        for line in config.lines() {
             if line.starts_with("file") {
                 if let Some(file_type) = line.strip_prefix("file ") {
-                    syntax_coloring.insert(file_type.trim().to_string(), colors.clone());
-                    colors.clear();
+                    let raw = std::mem::replace(&mut contexts, vec![RawContext::new("main".to_string(), None)]);
+                    syntax_coloring.insert(file_type.trim().to_string(), SyntaxHighlighter::resolve(raw));
+                    current = 0;
+                }
+            }
+            else if line.trim_end() == "end" {
+                current = 0;
+            }
+            else if line.starts_with("context") {
+                if let Some(cap) = context_reg.captures(line) {
+                    contexts.push(RawContext::new(cap["name"].to_string(), SyntaxHighlighter::parse_scope(&cap, scopes)));
+                    current = contexts.len() - 1;
                 }
             }
+            else if let Some(name) = line.strip_prefix("include ") {
+                contexts[current].rules.push(RawRule::Include(name.trim().to_string()));
+            }
+            else if let Some(rest) = line.strip_prefix("push ") {
+                if let Some((name,regex)) = rest.trim().split_once(char::is_whitespace) {
+                    if let Some(regex) = compile(regex.trim()) {
+                        contexts[current].rules.push(RawRule::Push(name.to_string(), regex));
+                    }
+                }
+            }
+            else if let Some(regex) = line.strip_prefix("pop ") {
+                if let Some(regex) = compile(regex.trim()) {
+                    contexts[current].rules.push(RawRule::Pop(regex));
+                }
+            }
+            else if line.starts_with("color") {
+                if let Some(cap) = color_reg.captures(line) {
+                    if let Some(scope) = SyntaxHighlighter::parse_scope(&cap, scopes) {
+                        if let Some(regex) = compile(&cap["regex"]) {
+                            contexts[current].rules.push(RawRule::Color(regex,scope));
+                        }
+                    }
+                }
+            }
+            else if line.starts_with("region") {
+                if let Some(cap) = region_reg.captures(line) {
+                    if let (Some(start),Some(end)) = (compile(&cap["start"]),compile(&cap["end"])) {
+                        let name = format!("region {}",contexts.len());
+                        let mut region = RawContext::new(name.clone(), SyntaxHighlighter::parse_scope(&cap, scopes));
+                        region.rules.push(RawRule::Pop(end));
+                        contexts.push(region);
+                        contexts[current].rules.push(RawRule::Push(name, start));
+                    }
+                }
+            }
+        }
+
+        errors
+    }
+
+    fn compile(regex: &str) -> Result<Regex,String> {
+        // Syntax errors come with a drawing of the pattern; the last line says what's wrong.
+        Regex::from_str(regex).map_err(|err| {
+            let err = err.to_string();
+            let reason = err.lines().last().unwrap_or("").trim_start_matches("error: ").to_string();
+            format!("bad regex '{}': {}",regex,reason)
+        })
+    }
 
-            if line.starts_with("color") {
-                if let Some(cap) = color_reg.captures(&line) {
-                    if let Some(r) = cap.name("r").map(|r| r.as_str().parse::<u8>().ok()).flatten() {
-                        if let Some(g) = cap.name("g").map(|g| g.as_str().parse::<u8>().ok()).flatten() {
-                            if let Some(b) = cap.name("b").map(|b| b.as_str().parse::<u8>().ok()).flatten() {
-                                if let Some(regex) = cap.name("regex").map(|regex| regex::Regex::from_str(regex.as_str()).ok()).flatten() {
-                                    colors.push((regex,Color::from((r,g,b))));
-                                }
-                                else {
-                                    println!("{:?} err occured",regex::Regex::from_str(cap.name("regex").unwrap().as_str()).err());
-                                    let _ = crossterm::event::read();
-                                }
-                            }
+    fn parse_scope(cap: &regex::Captures,scopes: &mut Vec<String>) -> Option<Scope> {
+        let scope = cap.name("scope")?.as_str();
+        if !scope.starts_with("rgb(") {
+            let index = scopes.iter().position(|name| name == scope).unwrap_or_else(|| {
+                scopes.push(scope.to_string());
+                scopes.len() - 1
+            });
+            return Some(Scope::Named(index));
+        }
+
+        let r = cap.name("r").and_then(|r| r.as_str().parse::<u8>().ok())?;
+        let g = cap.name("g").and_then(|g| g.as_str().parse::<u8>().ok())?;
+        let b = cap.name("b").and_then(|b| b.as_str().parse::<u8>().ok())?;
+        Some(Scope::Rgb(Color::from((r,g,b))))
+    }
+
+    pub fn scope_name(&self,index: usize) -> &str {
+        &self.scopes[index]
+    }
+
+    // Turns context names into indices and copies included rules in place. Rules naming a context
+    // that doesn't exist are dropped.
+    fn resolve(raw: Vec<RawContext>) -> Syntax {
+        let index_of = |name: &str| raw.iter().position(|context| context.name == name);
+
+        fn flatten(raw: &[RawContext],context: usize,index_of: &dyn Fn(&str) -> Option<usize>,visiting: &mut Vec<usize>,rules: &mut Vec<Rule>) {
+            if visiting.contains(&context) {
+                return;
+            }
+            visiting.push(context);
+            for rule in &raw[context].rules {
+                match rule {
+                    RawRule::Color(regex,scope) => rules.push(Rule::Color { regex: regex.clone(), scope: *scope }),
+                    RawRule::Push(name,regex) => {
+                        if let Some(target) = index_of(name) {
+                            rules.push(Rule::Push { regex: regex.clone(), context: target });
+                        }
+                    },
+                    RawRule::Pop(regex) => rules.push(Rule::Pop { regex: regex.clone() }),
+                    RawRule::Include(name) => {
+                        if let Some(included) = index_of(name) {
+                            flatten(raw, included, index_of, visiting, rules);
                         }
                     }
                 }
             }
+            visiting.pop();
         }
 
-        Self {
-            buffer: String::new(),
-            syntax_coloring
+        let contexts = (0..raw.len()).map(|context| {
+            let mut rules = Vec::new();
+            flatten(&raw, context, &index_of, &mut Vec::new(), &mut rules);
+            Context {
+                name: raw[context].name.clone(),
+                scope: raw[context].scope,
+                rules
+            }
+        }).collect();
+
+        Syntax {
+            contexts
         }
     }
 
-    pub fn reset(&mut self) {
-        self.buffer.clear();
+    // Brings the cache up to date for every line before `end_line`. Lines that weren't edited and start
+    // in the same state as last time are reused, so after an edit only the lines from the change until
+    // the states line up again are highlighted afresh. Returns how many lines that was.
+    pub fn update(&self,text: &Rope,file_type: &Option<String>,cache: &mut HighlightCache,end_line: usize) -> usize {
+        if cache.file_type != *file_type || cache.lines.len() != text.len_lines() {
+            cache.clear();
+            cache.lines.resize(text.len_lines(), None);
+            cache.file_type = file_type.clone();
+        }
+
+        let syntax = match file_type.as_ref().and_then(|file_type| self.syntax_coloring.get(file_type)) {
+            Some(syntax) => syntax,
+            None => return 0
+        };
+
+        let end_line = end_line.min(text.len_lines());
+        let mut highlighted = 0;
+        if cache.valid_to > 0 && cache.lines[cache.valid_to - 1].is_none() {
+            cache.valid_to = 0;
+        }
+
+        // Every line before the one being looked at has an up to date entry, so its end state is the
+        // start state for this one.
+        for line in cache.valid_to..end_line {
+            let state = match line.checked_sub(1) {
+                Some(previous) => cache.lines[previous].as_ref().unwrap().end,
+                None => 0
+            };
+            if let Some(cached) = &cache.lines[line] {
+                if cached.start == state {
+                    continue;
+                }
+            }
+
+            let mut line_text = text.line(line).to_string();
+            if line_text.ends_with('\n') {
+                line_text.pop();
+                if line_text.ends_with('\r') {
+                    line_text.pop();
+                }
+            }
+
+            let (spans,end) = SyntaxHighlighter::highlight_line(syntax, &line_text, cache.states[state].clone());
+            let end = cache.intern(end);
+            cache.lines[line] = Some(CachedLine { start: state, end, spans });
+            highlighted += 1;
+        }
+
+        cache.valid_to = cache.valid_to.max(end_line);
+        highlighted
     }
 
+    // Colors one line, starting in `state`, and returns its spans along with the state it ends in.
+    pub fn highlight_line(syntax: &Syntax,line: &str,mut state: LineState) -> (Vec<Span>,LineState) {
+        let mut spans = Vec::new();
+        let mut push = |start: usize,end: usize,scope: Option<Scope>| {
+            if let Some(scope) = scope {
+                if start < end {
+                    spans.push(Span { start: line[..start].chars().count(), end: line[..end].chars().count(), scope });
+                }
+            }
+        };
 
-    pub fn highlight<T>(&mut self,window: &mut T,deviation: usize,file_type: &Option<String>)
-        where T: Drawable 
-    {
-        if let Some(file_type) = file_type {
-            let window_string = window.to_string();
+        let mut searches = Searches::new(line);
+        let mut pos = 0;
+        loop {
+            let context_id = state.context();
+            let context = &syntax.contexts[context_id];
 
-            if let Some(syntax_coloring) = self.syntax_coloring.get(file_type) {
-                for (regex,color) in syntax_coloring {
-                    for caps in regex.captures_iter(&window_string) {
-                        if let Some(cap) = caps.name("color") {
-                            let x = (cap.start() - deviation) % window.width();
-                            let y = (cap.start() - deviation) / window.width();
-                            if y != 0 && x >= 4 {
-                                window.color(cap.start() - deviation, cap.end() - deviation, *color);
-                            }
-                        }                
+            // What the color rules match from here on, which pushes and pops can't start inside of.
+            let mut colored = Colored::new(context, pos);
+
+            // The earliest push or pop, with ties going to the rule written first. Empty matches are
+            // skipped so this always moves forward.
+            let mut next: Option<(usize,usize,&Rule)> = None;
+            for (i,rule) in context.rules.iter().enumerate() {
+                let regex = match rule {
+                    Rule::Push { regex, .. } | Rule::Pop { regex } => regex,
+                    Rule::Color { .. } => continue
+                };
+                let mut at = pos;
+                while let Some((start,end,_,_)) = searches.find((context_id,i), regex, at) {
+                    if next.is_some_and(|(best,_,_)| start >= best) {
+                        break;
+                    }
+                    colored.extend(&mut searches, context_id, context, start);
+                    if start < end && !colored.masks(start) {
+                        next = Some((start,end,rule));
+                        break;
                     }
+                    at = match line[start..].chars().next() {
+                        Some(c) => start + c.len_utf8(),
+                        None => break
+                    };
+                }
+            }
+
+            let segment_end = next.map_or(line.len(), |(start,_,_)| start);
+            colored.extend(&mut searches, context_id, context, segment_end);
+            push(pos, segment_end, context.scope);
+            for (_,(_,end,part_start,part_end),scope) in colored.in_rule_order() {
+                if end <= segment_end {
+                    push(part_start, part_end, Some(scope));
                 }
             }
+
+            match next {
+                Some((start,end,Rule::Push { context: target, .. })) => {
+                    push(start, end, syntax.contexts[*target].scope);
+                    if state.stack.len() < MAX_DEPTH {
+                        state.stack.push(*target);
+                    }
+                    pos = end;
+                },
+                Some((start,end,_)) => {
+                    push(start, end, context.scope);
+                    state.stack.pop();
+                    pos = end;
+                },
+                None => break
+            }
         }
+
+        (spans,state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAMMAR: &str = r#"
+context escapes
+    color string.escape \\.
+end
+context string string
+    include escapes
+    pop "
+end
+context comment comment
+    push comment /\*
+    pop \*/
+end
+push string "
+push comment /\*
+color ident [a-z"]+
+color keyword \bif\b
+file t
+"#;
+
+    // Highlights `lines` one after another, returning each line as runs of (text, scope name), with
+    // an empty name for text no rule colors.
+    fn highlight(lines: &[&str]) -> Vec<Vec<(String,String)>> {
+        let mut highlighter = SyntaxHighlighter::new();
+        assert!(highlighter.load("test", GRAMMAR).is_empty());
+        let syntax = &highlighter.syntax_coloring["t"];
+
+        let mut state = LineState::new();
+        lines.iter().map(|line| {
+            let (spans,end) = SyntaxHighlighter::highlight_line(syntax, line, state.clone());
+            state = end;
+
+            // Later spans draw over earlier ones, as they do on screen.
+            let mut scopes = vec![String::new(); line.chars().count()];
+            for span in spans {
+                let name = match span.scope {
+                    Scope::Named(index) => highlighter.scope_name(index).to_string(),
+                    Scope::Rgb(_) => "rgb".to_string()
+                };
+                for scope in &mut scopes[span.start..span.end] {
+                    *scope = name.clone();
+                }
+            }
+
+            let mut runs: Vec<(String,String)> = Vec::new();
+            for (c,scope) in line.chars().zip(scopes) {
+                match runs.last_mut() {
+                    Some((text,last)) if *last == scope => text.push(c),
+                    _ => runs.push((c.to_string(),scope))
+                }
+            }
+            runs
+        }).collect()
+    }
+
+    fn runs(expected: &[(&str,&str)]) -> Vec<(String,String)> {
+        expected.iter().map(|&(text,scope)| (text.to_string(),scope.to_string())).collect()
+    }
+
+    #[test]
+    fn color() {
+        assert_eq!(highlight(&["if x"]), vec![runs(&[("if","keyword"),(" ",""),("x","ident")])]);
+    }
+
+    #[test]
+    fn push_and_pop() {
+        assert_eq!(highlight(&["a \"b c\" d"]), vec![runs(&[("a","ident"),(" ",""),("\"b c\"","string"),(" ",""),("d","ident")])]);
+    }
+
+    #[test]
+    fn include() {
+        assert_eq!(highlight(&[r#""a\"b" c"#]), vec![runs(&[("\"a","string"),("\\\"","string.escape"),("b\"","string"),(" ",""),("c","ident")])]);
+    }
+
+    #[test]
+    fn nested_block_comments() {
+        assert_eq!(highlight(&["/* a /* b */ c */ d"]), vec![runs(&[("/* a /* b */ c */","comment"),(" ",""),("d","ident")])]);
+    }
+
+    #[test]
+    fn comment_across_lines() {
+        assert_eq!(highlight(&["x /* a /* b", "c */ d */ e"]), vec![
+            runs(&[("x","ident"),(" ",""),("/* a /* b","comment")]),
+            runs(&[("c */ d */","comment"),(" ",""),("e","ident")])
+        ]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn comment_opener_in_string() {
+        assert_eq!(highlight(&["\"a /* b\" c", "d"]), vec![
+            runs(&[("\"a /* b\"","string"),(" ",""),("c","ident")]),
+            runs(&[("d","ident")])
+        ]);
+    }
+
+    #[test]
+    fn color_match_across_a_pop() {
+        // `[a-z"]+` matches all of `"ab"cd` from the start of the line, but the string is pushed first,
+        // and the match found after the pop is just `cd`.
+        assert_eq!(highlight(&["\"ab\"cd"]), vec![runs(&[("\"ab\"","string"),("cd","ident")])]);
+    }
+
+    #[test]
+    fn edits_rehighlight_until_states_converge() {
+        let mut highlighter = SyntaxHighlighter::new();
+        highlighter.load("test", GRAMMAR);
+        let file_type = Some("t".to_string());
+        let mut text = Rope::from_str("a\nb\n/* c\nd */\ne\nf");
+        let mut cache = HighlightCache::new();
+        let lines = text.len_lines();
+        assert_eq!(highlighter.update(&text, &file_type, &mut cache, lines), lines);
+
+        // Replaces `line` with `with`, telling the cache about it like a document does.
+        let edit = |text: &mut Rope,cache: &mut HighlightCache,line: usize,with: &str| {
+            let start = text.line_to_char(line);
+            let end = start + text.line(line).len_chars() - 1;
+            text.remove(start..end);
+            text.insert(start, with);
+            cache.edited(line, lines, lines);
+            highlighter.update(text, &file_type, cache, lines)
+        };
+
+        // An edit that leaves the line's end state alone only redoes that line, inside a comment too.
+        assert_eq!(edit(&mut text, &mut cache, 0, "aa"), 1);
+        assert_eq!(edit(&mut text, &mut cache, 3, "dd */"), 1);
+        // Closing the comment a line early changes the next line's start state, but not its end state.
+        assert_eq!(edit(&mut text, &mut cache, 2, "/* c */"), 2);
+        // Opening a comment runs on until the states line up, here at the `*/` left over on line 3.
+        assert_eq!(edit(&mut text, &mut cache, 1, "/* b"), 3);
+        // A comment that's never closed reaches every line after it.
+        assert_eq!(edit(&mut text, &mut cache, 3, "dd"), lines - 3);
+    }
+
+    #[test]
+    fn escape_keeps_string_open() {
+        assert_eq!(highlight(&[r#""\" if"#]), vec![runs(&[("\"","string"),("\\\"","string.escape"),(" if","string")])]);
+    }
+}
@@ -0,0 +1,19 @@
+pub mod editor;
+pub mod windowing;
+pub mod lexer;
+pub mod theme;
+pub mod color;
+pub mod config;
+pub mod settings;
+pub mod editorconfig;
+pub mod history;
+pub mod clipboard;
+pub mod search;
+pub mod status;
+pub mod save;
+pub mod format;
+pub mod error;
+pub mod unicode;
+
+#[macro_use]
+extern crate lazy_static;
@@ -1,19 +1,53 @@
-mod editor;
-mod windowing;
-mod lexer;
-
-#[macro_use]
-extern crate lazy_static;
-
-use crate::windowing::*;
-use crate::windowing::backends::CharGrid;
+use vce::windowing::*;
+use vce::windowing::backends::CharGrid;
+use vce::save::Backup;
+use vce::status::MessageKind;
+use vce::color::ColorMode;
 
 fn main() {
     let mut window: TermWindow<CharGrid> = TermWindow::new();
     let mut iter = std::env::args();
     iter.next();
-    for file in iter {
-        window.editor.open(file);
+    while let Some(arg) = iter.next() {
+        if arg == "--osc52" {
+            window.editor.clipboard.osc52 = true;
+        }
+        else if arg == "--no-alt-screen" {
+            window.alternate_screen = false;
+        }
+        else if arg == "--colors" {
+            match iter.next().as_deref().and_then(ColorMode::from_name) {
+                Some(mode) => window.editor.set_forced_color_mode(mode),
+                None => window.editor.set_message(MessageKind::Error, "Usage: --colors truecolor|256|16|off".to_string())
+            }
+        }
+        else if arg == "--config" {
+            if let Some(path) = iter.next() {
+                window.editor.config_file = Some(path.into());
+                window.editor.load_config();
+            }
+        }
+        else if arg == "--theme" {
+            if let Some(name) = iter.next() {
+                if let Err(err) = window.editor.set_theme(&name) {
+                    window.editor.set_message(MessageKind::Error, err.to_string());
+                }
+            }
+        }
+        else if arg == "--backup" {
+            window.editor.backup = Backup::Tilde;
+        }
+        else if arg == "--backup-dir" {
+            if let Some(dir) = iter.next() {
+                window.editor.backup = Backup::Dir(dir.into());
+            }
+        }
+        else if let Err(err) = window.editor.open(arg) {
+            window.editor.set_message(MessageKind::Error, err.to_string());
+        }
+    }
+    if let Err(err) = window.start() {
+        eprintln!("vce: {}",err);
+        std::process::exit(1);
     }
-    let _ = window.start();
 }
\ No newline at end of file
@@ -0,0 +1,113 @@
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,BufWriter};
+use std::path::{Path,PathBuf};
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Backup {
+    None,
+    // Keep the previous version next to the file as `file~`.
+    Tilde,
+    // Keep the previous version in a directory, named after its full path with separators replaced by '%'.
+    Dir(PathBuf)
+}
+
+// Writes a file by filling a temp file in the same directory, syncing it and renaming it over the
+// original, so a crash or full disk part way through never leaves a half-written file behind.
+pub fn atomic_write<F>(path: &Path,backup: &Backup,write: F) -> io::Result<()>
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<()>
+{
+    // Save through symlinks rather than replacing them with a regular file.
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let original = fs::metadata(&target).ok();
+
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from(".")
+    };
+    let file_name = target.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+
+    let temp_path = dir.join(format!(".{}.vce-{}.tmp",file_name.to_string_lossy(),std::process::id()));
+
+    let result = write_temp(&temp_path, original.as_ref(), write).and_then(|()| {
+        if original.is_some() {
+            make_backup(&target, backup)?;
+        }
+        fs::rename(&temp_path, &target)
+    });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return result;
+    }
+
+    sync_dir(&dir);
+    Ok(())
+}
+
+fn write_temp<F>(temp_path: &Path,original: Option<&fs::Metadata>,write: F) -> io::Result<()>
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<()>
+{
+    let file = OpenOptions::new().write(true).create_new(true).open(temp_path)?;
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)?;
+
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    if let Some(original) = original {
+        copy_metadata(&file, temp_path, original)?;
+    }
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn copy_metadata(file: &File,temp_path: &Path,original: &fs::Metadata) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    // Changing the owner needs privileges we usually don't have, and the file is still ours then.
+    let _ = std::os::unix::fs::chown(temp_path, Some(original.uid()), Some(original.gid()));
+    file.set_permissions(original.permissions())
+}
+
+#[cfg(not(unix))]
+fn copy_metadata(file: &File,_temp_path: &Path,original: &fs::Metadata) -> io::Result<()> {
+    file.set_permissions(original.permissions())
+}
+
+fn make_backup(target: &Path,backup: &Backup) -> io::Result<()> {
+    let backup_path = match backup {
+        Backup::None => return Ok(()),
+        Backup::Tilde => {
+            let mut name = target.as_os_str().to_os_string();
+            name.push("~");
+            PathBuf::from(name)
+        },
+        Backup::Dir(dir) => {
+            fs::create_dir_all(dir)?;
+            dir.join(target.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "%"))
+        }
+    };
+
+    fs::copy(target, backup_path).map(|_| ())
+}
+
+// Where copies of unsaved documents go when the editor has to bail out.
+pub fn recovery_dir() -> PathBuf {
+    if let Some(state) = std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(state).join("vce").join("recovery");
+    }
+    if let Some(home) = std::env::var_os("HOME").filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(home).join(".local").join("state").join("vce").join("recovery");
+    }
+    std::env::temp_dir().join("vce-recovery")
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) {
+    // Makes the rename itself durable. Not every filesystem supports it, so failures are ignored.
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) {}
+
@@ -0,0 +1,142 @@
+use regex::{Regex,RegexBuilder};
+use ropey::Rope;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum SearchMode {
+    Plain,
+    CaseInsensitive,
+    Regex
+}
+
+impl SearchMode {
+    pub fn next(self) -> Self {
+        match self {
+            SearchMode::Plain => SearchMode::CaseInsensitive,
+            SearchMode::CaseInsensitive => SearchMode::Regex,
+            SearchMode::Regex => SearchMode::Plain
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SearchMode::Plain => "plain",
+            SearchMode::CaseInsensitive => "ignore case",
+            SearchMode::Regex => "regex"
+        }
+    }
+}
+
+pub struct Search {
+    pub query: String,
+    pub mode: SearchMode,
+    // Char ranges of every match in the document, in order.
+    pub matches: Vec<(usize,usize)>,
+    pub current: Option<usize>,
+    pub invalid: bool
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Search {
+    pub fn new() -> Self {
+        Self {
+            query: String::new(),
+            mode: SearchMode::Plain,
+            matches: Vec::new(),
+            current: None,
+            invalid: false
+        }
+    }
+
+    pub fn regex(&self) -> Result<Regex,regex::Error> {
+        match self.mode {
+            SearchMode::Plain => Regex::new(&regex::escape(&self.query)),
+            SearchMode::CaseInsensitive => RegexBuilder::new(&regex::escape(&self.query)).case_insensitive(true).build(),
+            SearchMode::Regex => RegexBuilder::new(&self.query).multi_line(true).build()
+        }
+    }
+
+    pub fn update(&mut self,text: &Rope) {
+        self.matches.clear();
+        self.current = None;
+        self.invalid = false;
+
+        if self.query.is_empty() {
+            return;
+        }
+
+        match self.regex() {
+            Ok(regex) => {
+                let haystack = text.to_string();
+                for m in regex.find_iter(&haystack) {
+                    if m.start() != m.end() {
+                        self.matches.push((text.byte_to_char(m.start()),text.byte_to_char(m.end())));
+                    }
+                }
+            },
+            Err(_) => self.invalid = true
+        }
+    }
+
+    // Every match paired with the text it should be replaced with, in document order.
+    // Regex mode expands $1 and ${name} from the match's capture groups.
+    pub fn replacements(&self,text: &Rope,replacement: &str) -> Vec<(usize,usize,String)> {
+        let regex = match self.regex() {
+            Ok(regex) => regex,
+            Err(_) => return Vec::new()
+        };
+
+        let haystack = text.to_string();
+        let mut replacements = Vec::new();
+        for caps in regex.captures_iter(&haystack) {
+            let m = caps.get(0).unwrap();
+            if m.start() == m.end() {
+                continue;
+            }
+
+            let mut with = String::new();
+            if self.mode == SearchMode::Regex {
+                caps.expand(replacement, &mut with);
+            }
+            else {
+                with.push_str(replacement);
+            }
+            replacements.push((text.byte_to_char(m.start()),text.byte_to_char(m.end()),with));
+        }
+        replacements
+    }
+
+    // First match starting at or after `pos`, wrapping around to the top.
+    pub fn first_from(&mut self,pos: usize) -> Option<(usize,usize)> {
+        if self.matches.is_empty() {
+            return None;
+        }
+        let index = self.matches.iter().position(|m| m.0 >= pos).unwrap_or(0);
+        self.current = Some(index);
+        Some(self.matches[index])
+    }
+
+    pub fn next(&mut self,pos: usize) -> Option<(usize,usize)> {
+        self.first_from(pos + 1)
+    }
+
+    pub fn prev(&mut self,pos: usize) -> Option<(usize,usize)> {
+        if self.matches.is_empty() {
+            return None;
+        }
+        let index = self.matches.iter().rposition(|m| m.0 < pos).unwrap_or(self.matches.len() - 1);
+        self.current = Some(index);
+        Some(self.matches[index])
+    }
+
+    // Matches overlapping the char range [start, end).
+    pub fn matches_in(&self,start: usize,end: usize) -> &[(usize,usize)] {
+        let first = self.matches.partition_point(|m| m.1 <= start);
+        let last = self.matches.partition_point(|m| m.0 < end);
+        &self.matches[first..last.max(first)]
+    }
+}
@@ -0,0 +1,250 @@
+use serde::Deserialize;
+use toml::Value;
+
+use std::collections::HashMap;
+
+const KEYS: [&str; 7] = ["indent_style","indent_width","auto_pair","pairs","line_numbers","scroll_margin","color_mode"];
+
+#[derive(Debug,Clone,Copy,PartialEq,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndentStyle {
+    Spaces,
+    Tabs
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineNumbers {
+    Absolute,
+    // Distance from the cursor's line, with the cursor's own line numbered absolutely.
+    Relative,
+    Off
+}
+
+// What `settings.toml` can set. Anything a file leaves out keeps the value from the layer below it.
+#[derive(Debug,Clone,Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub indent_style: IndentStyle,
+    pub indent_width: usize,
+    pub auto_pair: bool,
+    // Two-character strings, the opening character followed by the closing one.
+    pub pairs: Vec<String>,
+    pub line_numbers: LineNumbers,
+    pub scroll_margin: usize,
+    // "auto" or a `ColorMode` name.
+    pub color_mode: String
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            indent_style: IndentStyle::Spaces,
+            indent_width: 4,
+            auto_pair: true,
+            pairs: vec!["()".to_string(),"[]".to_string(),"{}".to_string()],
+            line_numbers: LineNumbers::Absolute,
+            scroll_margin: 0,
+            color_mode: "auto".to_string()
+        }
+    }
+}
+
+impl Settings {
+    // The pair `c` opens, if it's the first character of one.
+    pub fn closing_pair(&self,c: char) -> Option<char> {
+        self.pairs.iter().filter_map(|pair| Settings::split_pair(pair)).find(|&(open,_)| open == c).map(|(_,close)| close)
+    }
+
+    pub fn is_closing_pair(&self,c: char) -> bool {
+        self.pairs.iter().filter_map(|pair| Settings::split_pair(pair)).any(|(_,close)| close == c)
+    }
+
+    fn split_pair(pair: &str) -> Option<(char,char)> {
+        let mut chars = pair.chars();
+        match (chars.next(),chars.next(),chars.next()) {
+            (Some(open),Some(close),None) => Some((open,close)),
+            _ => None
+        }
+    }
+}
+
+// The settings for every file type, built from the layered settings files. Per-language tables live
+// under `[language.<file type>]` and override the top level keys for documents of that type.
+pub struct SettingsTable {
+    base: Settings,
+    languages: HashMap<String,Settings>
+}
+
+impl Default for SettingsTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SettingsTable {
+    pub fn new() -> Self {
+        Self {
+            base: Settings::default(),
+            languages: HashMap::new()
+        }
+    }
+
+    // Merges the (source, text) layers in order, later ones winning. Returns the problems found, each
+    // prefixed with its source; a layer that doesn't parse is skipped, and a key that's unknown or has
+    // the wrong type is left out.
+    pub fn load(layers: &[(String,String)]) -> (Self,Vec<String>) {
+        let mut errors = Vec::new();
+        let mut merged = Value::Table(toml::value::Table::new());
+        for (source,text) in layers {
+            match text.parse::<Value>() {
+                Ok(mut layer) => {
+                    drop_bad_keys(&mut layer, source, &mut errors);
+                    merge(&mut merged, layer);
+                },
+                Err(err) => errors.push(format!("{}: {}",source,err))
+            }
+        }
+
+        let languages = match &mut merged {
+            Value::Table(table) => table.remove("language"),
+            _ => None
+        };
+
+        let mut settings = SettingsTable::new();
+        match merged.clone().try_into::<Settings>() {
+            Ok(base) => settings.base = base,
+            Err(err) => errors.push(format!("settings: {}",err))
+        }
+
+        if let Some(Value::Table(languages)) = languages {
+            for (file_type,overrides) in languages {
+                let mut language = merged.clone();
+                merge(&mut language, overrides);
+                match language.try_into::<Settings>() {
+                    Ok(language) => {
+                        settings.languages.insert(file_type, language);
+                    },
+                    Err(err) => errors.push(format!("settings for {}: {}",file_type,err))
+                }
+            }
+        }
+
+        (settings,errors)
+    }
+
+    pub fn base(&self) -> &Settings {
+        &self.base
+    }
+
+    pub fn for_type(&self,file_type: &Option<String>) -> &Settings {
+        file_type.as_ref().and_then(|file_type| self.languages.get(file_type)).unwrap_or(&self.base)
+    }
+}
+
+// Layers `over` onto `base`, merging tables key by key and replacing everything else.
+fn merge(base: &mut Value,over: Value) {
+    match (base,over) {
+        (Value::Table(base),Value::Table(over)) => {
+            for (key,value) in over {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        },
+        (base,over) => *base = over
+    }
+}
+
+// Takes out the keys that aren't settings or don't hold the right type of value, so a mistake costs
+// only that one line of the file.
+fn drop_bad_keys(layer: &mut Value,source: &str,errors: &mut Vec<String>) {
+    let table = match layer {
+        Value::Table(table) => table,
+        _ => return
+    };
+
+    let mut check = |table: &mut toml::value::Table,prefix: &str| {
+        let keys: Vec<String> = table.keys().cloned().collect();
+        for key in keys {
+            let problem = if KEYS.contains(&key.as_str()) {
+                // Every key has a default, so the key can be checked on its own.
+                let mut single = toml::value::Table::new();
+                single.insert(key.clone(), table[&key].clone());
+                match Value::Table(single).try_into::<Settings>() {
+                    Ok(_) => continue,
+                    Err(err) => format!("bad value for `{}{}`: {}",prefix,key,err)
+                }
+            }
+            else {
+                format!("unknown setting `{}{}`",prefix,key)
+            };
+            table.remove(&key);
+            errors.push(format!("{}: {}",source,problem));
+        }
+    };
+
+    let languages = table.remove("language");
+    check(table, "");
+    match languages {
+        Some(Value::Table(mut languages)) => {
+            let mut not_tables = Vec::new();
+            for (file_type,language) in languages.iter_mut() {
+                match language {
+                    Value::Table(language) => check(language, &format!("language.{}.",file_type)),
+                    _ => not_tables.push(file_type.clone())
+                }
+            }
+            for file_type in not_tables {
+                languages.remove(&file_type);
+                errors.push(format!("{}: `language.{}` should be a table",source,file_type));
+            }
+            table.insert("language".to_string(), Value::Table(languages));
+        },
+        Some(_) => errors.push(format!("{}: `language` should be a table",source)),
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(layers: &[(&str,&str)]) -> (SettingsTable,Vec<String>) {
+        let layers: Vec<(String,String)> = layers.iter().map(|&(source,text)| (source.to_string(),text.to_string())).collect();
+        SettingsTable::load(&layers)
+    }
+
+    #[test]
+    fn bad_value_drops_only_that_key() {
+        let (settings,errors) = load(&[
+            ("base", "indent_width = 2\nauto_pair = false"),
+            ("user", "indent_width = \"4\"\nscroll_margin = 3\n[language.go]\nindent_style = \"sideways\"\nindent_width = 8")
+        ]);
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("user: bad value for `indent_width`"));
+        assert!(errors[1].starts_with("user: bad value for `language.go.indent_style`"));
+
+        let base = settings.base();
+        assert_eq!(base.indent_width, 2);
+        assert!(!base.auto_pair);
+        assert_eq!(base.scroll_margin, 3);
+
+        let go = settings.for_type(&Some("go".to_string()));
+        assert_eq!(go.indent_width, 8);
+        assert_eq!(go.indent_style, IndentStyle::Spaces);
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_source() {
+        let (settings,errors) = load(&[("project", "indent_widht = 2\nline_numbers = \"relative\"\nlanguage = 3")]);
+        assert_eq!(errors, vec![
+            "project: unknown setting `indent_widht`".to_string(),
+            "project: `language` should be a table".to_string()
+        ]);
+        assert_eq!(settings.base().line_numbers, LineNumbers::Relative);
+    }
+}
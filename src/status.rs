@@ -0,0 +1,43 @@
+use std::time::{Duration,Instant};
+
+// How long a message stays on screen before the message line is freed up again.
+const MESSAGE_TIMEOUT: Duration = Duration::from_millis(4000);
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum MessageKind {
+    Info,
+    Warn,
+    Error
+}
+
+pub struct Message {
+    pub kind: MessageKind,
+    pub text: String,
+    shown_at: Instant
+}
+
+impl Message {
+    pub fn new(kind: MessageKind,text: String) -> Self {
+        Self {
+            kind,
+            text,
+            shown_at: Instant::now()
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.shown_at.elapsed() >= MESSAGE_TIMEOUT
+    }
+
+}
+
+impl MessageKind {
+    // The theme scope the message is drawn with.
+    pub fn scope(self) -> &'static str {
+        match self {
+            MessageKind::Info => "ui.message.info",
+            MessageKind::Warn => "ui.message.warn",
+            MessageKind::Error => "ui.message.error"
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use crossterm::style::Color;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::Path;
+
+use crate::windowing::Style;
+use crate::error::EditorError;
+
+// Maps scope names to styles. Grammars name what they match (`keyword`, `string`, `comment`...) and
+// the theme decides how that looks, so one theme works for every language. The editor's own parts use
+// scopes under `ui.`, like `ui.gutter` or `ui.statusbar`.
+pub struct Theme {
+    pub name: String,
+    styles: HashMap<String,Style>
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Theme {
+    // The built-in theme, which also fills in anything a theme file leaves out.
+    pub fn new() -> Self {
+        let mut styles = HashMap::new();
+        let defaults = [
+            ("ui.text", Style::new()),
+            ("ui.gutter", Style::new()),
+            ("ui.tabbar", Style::new()),
+            ("ui.tabbar.active", Style::new().fg(Color::Red)),
+            ("ui.selection", Style::new().bg(Color::DarkBlue)),
+            ("ui.search", Style::new().bg(Color::DarkYellow)),
+            ("ui.search.current", Style::new().bg(Color::DarkMagenta)),
+            ("ui.statusbar", Style::new().bg(Color::DarkGrey)),
+            ("ui.prompt", Style::new().fg(Color::Cyan)),
+            ("ui.message.info", Style::new()),
+            ("ui.message.warn", Style::new().fg(Color::Yellow)),
+            ("ui.message.error", Style::new().fg(Color::Red)),
+            ("keyword", Style::new().fg(Color::Magenta)),
+            ("string", Style::new().fg(Color::Green)),
+            ("comment", Style::new().fg(Color::DarkGrey).italic()),
+            ("type", Style::new().fg(Color::Yellow)),
+            ("function", Style::new().fg(Color::Blue)),
+            ("number", Style::new().fg(Color::Cyan)),
+            ("constant", Style::new().fg(Color::Cyan)),
+            ("operator", Style::new()),
+            ("variable", Style::new()),
+            ("macro", Style::new().fg(Color::DarkCyan)),
+            ("attribute", Style::new().fg(Color::DarkYellow))
+        ];
+        for (scope,style) in defaults.iter() {
+            styles.insert(scope.to_string(), *style);
+        }
+
+        Self {
+            name: "default".to_string(),
+            styles
+        }
+    }
+
+    // A theme file has one scope per line followed by what it should look like:
+    //
+    //   keyword fg=magenta bold
+    //   ui.statusbar fg=#000000 bg=rgb(200,200,200)
+    //
+    // Colors are crossterm names (`dark_grey`, `red`...), `reset`, `#rrggbb` or `rgb(r,g,b)`.
+    // The attributes are `bold`, `italic`, `underline` and `reverse`. `#` starts a comment.
+    pub fn parse(name: &str,text: &str) -> Result<Self,String> {
+        let mut theme = Theme::new();
+        theme.name = name.to_string();
+
+        for (num,line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let scope = words.next().unwrap();
+            let mut style = Style::new();
+            for word in words {
+                style = match word {
+                    "bold" => style.bold(),
+                    "italic" => style.italic(),
+                    "underline" => style.underline(),
+                    "reverse" => style.reverse(),
+                    _ => {
+                        let color = |value: &str| Theme::parse_color(value).ok_or_else(|| format!("line {}: bad color '{}'",num + 1,value));
+                        if let Some(value) = word.strip_prefix("fg=") {
+                            style.fg(color(value)?)
+                        }
+                        else if let Some(value) = word.strip_prefix("bg=") {
+                            style.bg(color(value)?)
+                        }
+                        else {
+                            return Err(format!("line {}: unknown attribute '{}'",num + 1,word));
+                        }
+                    }
+                };
+            }
+            theme.styles.insert(scope.to_string(), style);
+        }
+
+        Ok(theme)
+    }
+
+    pub fn load(path: &Path) -> Result<Self,EditorError> {
+        let text = std::fs::read_to_string(path).map_err(|err| EditorError::io(&path.display().to_string(), err))?;
+        let name = path.file_stem().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        Theme::parse(&name, &text).map_err(|err| EditorError::Config(format!("{}: {}",path.display(),err)))
+    }
+
+    fn parse_color(value: &str) -> Option<Color> {
+        if value == "reset" {
+            return Some(Color::Reset);
+        }
+        if let Some(hex) = value.strip_prefix('#') {
+            if hex.len() != 6 {
+                return None;
+            }
+            let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+            return Some(Color::from((channel(0)?,channel(2)?,channel(4)?)));
+        }
+        if let Some(rgb) = value.strip_prefix("rgb(").and_then(|rest| rest.strip_suffix(')')) {
+            let channels = rgb.split(',').map(|c| c.trim().parse::<u8>().ok()).collect::<Option<Vec<_>>>()?;
+            if channels.len() != 3 {
+                return None;
+            }
+            return Some(Color::from((channels[0],channels[1],channels[2])));
+        }
+        Color::try_from(value).ok()
+    }
+
+    // The style for a scope, falling back to its parents: `keyword.control` uses `keyword` if it has no style of its own.
+    pub fn style(&self,scope: &str) -> Style {
+        let mut scope = scope;
+        loop {
+            if let Some(style) = self.styles.get(scope) {
+                return *style;
+            }
+            match scope.rfind('.') {
+                Some(dot) => scope = &scope[..dot],
+                None => return Style::new()
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use std::borrow::Cow;
+
+// Columns a grapheme cluster takes up on screen. Control characters are drawn as one-column pictures,
+// and clusters the terminal would give no width at all still get a column so the cursor can land on them.
+pub fn width(grapheme: &str) -> usize {
+    if is_control(grapheme) {
+        1
+    }
+    else {
+        grapheme.width().clamp(1, 2)
+    }
+}
+
+pub fn str_width(string: &str) -> usize {
+    string.graphemes(true).map(width).sum()
+}
+
+// Columns a grapheme cluster in a line of text takes up when it starts at `column`. A tab reaches the
+// next multiple of `tab_width`.
+pub fn width_at(grapheme: &str,column: usize,tab_width: usize) -> usize {
+    if grapheme == "\t" {
+        tab_width - column % tab_width
+    }
+    else {
+        width(grapheme)
+    }
+}
+
+// Columns the start of a line takes up, with its tabs expanded.
+pub fn line_width(string: &str,tab_width: usize) -> usize {
+    string.graphemes(true).fold(0, |column,grapheme| column + width_at(grapheme, column, tab_width))
+}
+
+// What to put on screen for a grapheme cluster, so it takes up exactly `width(grapheme)` columns.
+pub fn symbol(grapheme: &str) -> Cow<'_,str> {
+    if is_control(grapheme) {
+        // Control characters would be interpreted by the terminal, so show their Unicode pictures.
+        let c = grapheme.chars().next().unwrap();
+        Cow::Owned(std::char::from_u32(0x2400 + c as u32).unwrap_or(c).to_string())
+    }
+    else if grapheme.width() == 0 {
+        Cow::Borrowed("\u{25CC}")
+    }
+    else {
+        Cow::Borrowed(grapheme)
+    }
+}
+
+// What to put on screen for a grapheme cluster in a line of text starting at `column`: tabs become
+// the spaces up to the next tab stop.
+pub fn symbol_at(grapheme: &str,column: usize,tab_width: usize) -> Cow<'_,str> {
+    if grapheme == "\t" {
+        Cow::Owned(" ".repeat(width_at(grapheme, column, tab_width)))
+    }
+    else {
+        symbol(grapheme)
+    }
+}
+
+fn is_control(grapheme: &str) -> bool {
+    grapheme.chars().next().is_some_and(|c| (c as u32) < 0x20)
+}
@@ -1,123 +1,341 @@
 use crate::editor::*;
+use crate::unicode;
+use crate::color::ColorMode;
 
-use crossterm::{event::{read,EnableMouseCapture,DisableMouseCapture},execute,terminal::{enable_raw_mode,disable_raw_mode}};
+use crossterm::{event::{read,poll,EnableMouseCapture,DisableMouseCapture},execute,terminal::{enable_raw_mode,disable_raw_mode,EnterAlternateScreen,LeaveAlternateScreen}};
 use std::io::{Write,stdout};
 use crossterm::style::Color;
 
+use unicode_segmentation::UnicodeSegmentation;
+
 use std::error::Error;
+use std::time::Duration;
+use std::panic::{self,AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
 
 pub struct TermWindow<T>
     where T: Drawable 
 {
     pub editor: Editor,
-    screen: T
+    // Run in the terminal's alternate screen so the user's scrollback is left alone.
+    pub alternate_screen: bool,
+    screen: T,
+    // Set by SIGHUP or SIGTERM, so the run loop can stop and save recovery copies like it does on a panic.
+    terminated: Arc<AtomicBool>
+}
+
+// How a cell is drawn. Colors left as None use the terminal's defaults.
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool
+}
+
+impl Style {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn fg(mut self,color: Color) -> Self {
+        self.fg = Some(color);
+        self
+    }
+
+    pub fn bg(mut self,color: Color) -> Self {
+        self.bg = Some(color);
+        self
+    }
+
+    pub fn bold(mut self) -> Self {
+        self.bold = true;
+        self
+    }
+
+    pub fn italic(mut self) -> Self {
+        self.italic = true;
+        self
+    }
+
+    pub fn underline(mut self) -> Self {
+        self.underline = true;
+        self
+    }
+
+    pub fn reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    // Layers `other` on top of this style: its colors win where it sets them and its attributes are added.
+    pub fn patch(self,other: Style) -> Self {
+        Self {
+            fg: other.fg.or(self.fg),
+            bg: other.bg.or(self.bg),
+            bold: self.bold || other.bold,
+            italic: self.italic || other.italic,
+            underline: self.underline || other.underline,
+            reverse: self.reverse || other.reverse
+        }
+    }
 }
 
 pub trait Drawable {
-    fn put_char(&mut self,x: usize,y: usize,c: char);
-    fn put_string(&mut self,x: usize,y: usize,string: &str) {
-        let mut i = 0;
-        for c in string.chars() {
-            self.put_char(x + i, y, c);
-            i += 1;
+    // Writes one grapheme cluster, replacing whatever style the cell had. Wide clusters take up the next cell too.
+    fn put_grapheme(&mut self,x: usize,y: usize,grapheme: &str,style: Style);
+    fn put_char(&mut self,x: usize,y: usize,c: char,style: Style) {
+        self.put_grapheme(x, y, c.encode_utf8(&mut [0; 4]), style);
+    }
+    // Returns how many columns the string took up.
+    fn put_string(&mut self,x: usize,y: usize,string: &str,style: Style) -> usize {
+        let mut column = x;
+        for grapheme in string.graphemes(true) {
+            self.put_grapheme(column, y, grapheme, style);
+            column += unicode::width(grapheme);
         }
+        column - x
     }
+    // Patches the style of the cells in [start, end), counted row by row, leaving their characters alone.
+    fn fill(&mut self,start: usize,end: usize,style: Style);
     fn resize(&mut self,width: usize,height: usize);
-    fn draw(&self) -> Result<(),Box<dyn Error>>;
+    fn set_color_mode(&mut self,mode: ColorMode);
+    fn draw(&mut self) -> Result<(),Box<dyn Error>>;
     fn clear(&mut self,c: char);
     fn new(width: usize,height: usize) -> Self;
-    fn color(&mut self, start: usize,end: usize, color: Color);
     fn width(&self) -> usize;
     fn height(&self) -> usize;
-    fn to_string(&self) -> String;
 }
 
 pub mod backends {
-    use crate::windowing::Drawable;
+    use crate::windowing::{Drawable,Style};
+    use crate::unicode;
+    use crate::color::ColorMode;
     use std::io::{Write,stdout};
-    use crossterm::style::Color;
+    use crossterm::{queue,style::{Color,Attribute,SetForegroundColor,SetBackgroundColor,SetAttribute},cursor::{Hide,Show,MoveTo}};
     use std::error::Error;
 
-    pub struct ColoringHint {
-        pub start: usize,
-        pub end: usize,
-        pub color: Color
+    #[derive(Clone,PartialEq)]
+    struct StyledCell {
+        // One grapheme cluster, or nothing if the cell is covered by a wide one to its left.
+        symbol: String,
+        style: Style
+    }
+
+    impl StyledCell {
+        fn blank() -> Self {
+            Self {
+                symbol: " ".to_string(),
+                style: Style::new()
+            }
+        }
+
+        fn set(&mut self,symbol: &str,style: Style) {
+            self.symbol.clear();
+            self.symbol.push_str(symbol);
+            self.style = style;
+        }
+
+        fn is_continuation(&self) -> bool {
+            self.symbol.is_empty()
+        }
     }
 
     pub struct CharGrid {
-        buffer: Vec<char>,
+        buffer: Vec<StyledCell>,
         width: usize,
         height: usize,
-        hints: Vec<ColoringHint>
+        // The frame currently on the terminal. Empty when it's unknown and everything has to be redrawn.
+        front: Vec<StyledCell>,
+        color_mode: ColorMode
+    }
+
+    impl CharGrid {
+        // Writes the escape sequences that take the terminal from the last rendered frame to this one,
+        // touching only the cells that changed.
+        pub fn render<W>(&mut self,out: &mut W) -> crossterm::Result<()>
+            where W: Write
+        {
+            let full = self.front.len() != self.buffer.len();
+
+            queue!(out,Hide)?;
+
+            // Where the terminal cursor is and which style is set, if known, so redundant moves and
+            // style changes can be skipped.
+            let mut cursor = None;
+            let mut pen: Option<Style> = None;
+            for (i,cell) in self.buffer.iter().enumerate() {
+                if cell.is_continuation() {
+                    continue;
+                }
+                // A wide character is redrawn whole if either of its halves changed.
+                let wide = i + 1 < self.buffer.len() && self.buffer[i + 1].is_continuation();
+                if !full && self.front[i] == *cell && (!wide || self.front[i + 1] == self.buffer[i + 1]) {
+                    continue;
+                }
+
+                let (x,y) = (i % self.width,i / self.width);
+                if cursor != Some(i) {
+                    queue!(out,MoveTo(x as u16,y as u16))?;
+                }
+                let style = self.terminal_style(cell.style);
+                if pen != Some(style) {
+                    set_style(out, pen, style)?;
+                    pen = Some(style);
+                }
+
+                write!(out,"{}",cell.symbol)?;
+                // The cursor doesn't move on to the next line by itself after the last column.
+                let advance = if wide { 2 } else { 1 };
+                cursor = if x + advance < self.width { Some(i + advance) } else { None };
+            }
+
+            if pen.is_some() && pen != Some(Style::new()) {
+                queue!(out,SetAttribute(Attribute::Reset))?;
+            }
+            queue!(out,Show)?;
+
+            self.front.clone_from(&self.buffer);
+            Ok(())
+        }
+
+        // Brings a style's colors down to what the terminal can show.
+        fn terminal_style(&self,style: Style) -> Style {
+            if self.color_mode == ColorMode::Off {
+                // Without colors, things marked by their background (selection, status bar) are shown in reverse video.
+                return Style {
+                    fg: None,
+                    bg: None,
+                    reverse: style.reverse || style.bg.is_some(),
+                    ..style
+                };
+            }
+
+            Style {
+                fg: style.fg.and_then(|color| self.color_mode.quantize(color)),
+                bg: style.bg.and_then(|color| self.color_mode.quantize(color)),
+                ..style
+            }
+        }
+    }
+
+    // Switches the terminal from the `current` style (unknown if None) to `style`.
+    fn set_style<W>(out: &mut W,current: Option<Style>,style: Style) -> crossterm::Result<()>
+        where W: Write
+    {
+        // Attributes can only be switched off all at once, and that resets the colors along with them.
+        let current = match current {
+            Some(current) if !drops_attribute(current, style) => current,
+            _ => {
+                queue!(out,SetAttribute(Attribute::Reset))?;
+                Style::new()
+            }
+        };
+
+        if style.fg != current.fg {
+            queue!(out,SetForegroundColor(style.fg.unwrap_or(Color::Reset)))?;
+        }
+        if style.bg != current.bg {
+            queue!(out,SetBackgroundColor(style.bg.unwrap_or(Color::Reset)))?;
+        }
+        if style.bold && !current.bold {
+            queue!(out,SetAttribute(Attribute::Bold))?;
+        }
+        if style.italic && !current.italic {
+            queue!(out,SetAttribute(Attribute::Italic))?;
+        }
+        if style.underline && !current.underline {
+            queue!(out,SetAttribute(Attribute::Underlined))?;
+        }
+        if style.reverse && !current.reverse {
+            queue!(out,SetAttribute(Attribute::Reverse))?;
+        }
+        Ok(())
+    }
+
+    fn drops_attribute(from: Style,to: Style) -> bool {
+        (from.bold && !to.bold) || (from.italic && !to.italic) || (from.underline && !to.underline) || (from.reverse && !to.reverse)
     }
 
     impl Drawable for CharGrid {
-        fn put_char(&mut self,x: usize, y: usize, c: char) {
-            if x < self.width && y < self.height {
-                self.buffer[x + y * self.width] = c;
+        fn put_grapheme(&mut self,x: usize,y: usize,grapheme: &str,style: Style) {
+            if x >= self.width || y >= self.height {
+                return;
+            }
+            let i = x + y * self.width;
+
+            // Overwriting either half of a wide character leaves the other half blank.
+            if self.buffer[i].is_continuation() {
+                self.buffer[i - 1].symbol = " ".to_string();
+            }
+            if x + 1 < self.width && self.buffer[i + 1].is_continuation() {
+                self.buffer[i + 1].symbol = " ".to_string();
+            }
+
+            if unicode::width(grapheme) == 2 {
+                if x + 1 >= self.width {
+                    // Doesn't fit in the last column.
+                    self.buffer[i].set(" ", style);
+                    return;
+                }
+                if x + 2 < self.width && self.buffer[i + 2].is_continuation() {
+                    self.buffer[i + 2].symbol = " ".to_string();
+                }
+                self.buffer[i + 1].set("", style);
+            }
+            self.buffer[i].set(grapheme, style);
+        }
+
+        fn fill(&mut self,start: usize,end: usize,style: Style) {
+            let end = end.min(self.buffer.len());
+            for cell in self.buffer.iter_mut().take(end).skip(start) {
+                cell.style = cell.style.patch(style);
             }
         }
 
         fn resize(&mut self,width: usize,height: usize) {
             self.width = width;
             self.height = height;
-            self.buffer = Vec::with_capacity(width * height);
-            for _ in 0..(width * height) {
-                self.buffer.push(' ');
+            self.buffer = vec![StyledCell::blank(); width * height];
+            self.front.clear();
+        }
+
+        fn set_color_mode(&mut self,mode: ColorMode) {
+            if mode != self.color_mode {
+                self.color_mode = mode;
+                self.front.clear();
             }
         }
 
         fn new(width: usize,height: usize) -> Self {
-            let mut buffer = Vec::with_capacity(width * height);
-
-            for _ in 0..(width * height) {
-                buffer.push(' ');
-            }
+            let buffer = vec![StyledCell::blank(); width * height];
 
             CharGrid {
                 buffer,
                 width,
                 height,
-                hints: Vec::new()
+                front: Vec::new(),
+                color_mode: ColorMode::detect()
             }
         }
 
         fn clear(&mut self,c: char) {
-            self.hints.clear();
-            for i in 0..(self.width * self.height) {
-                self.buffer[i] = c;
+            for cell in self.buffer.iter_mut() {
+                cell.set(c.encode_utf8(&mut [0; 4]), Style::new());
             }
         }
 
-        fn color(&mut self,start: usize,end: usize, color: Color) {
-            self.hints.push(ColoringHint {
-                start,end,color
-            });
-        }
-
-        fn draw(&self) -> Result<(),Box<dyn Error>> {
-            print!("{}",crossterm::cursor::Hide);
-            stdout().flush().unwrap();
-            print!("{}",crossterm::cursor::MoveTo(0,0));
-
-            let mut i = 0;
-            for c in &self.buffer {
-                for hint in &self.hints {
-                    if i >= hint.start && i < hint.end {
-                        crossterm::execute!(stdout(),crossterm::style::SetForegroundColor(hint.color))?;
-                    }
-                    if i != 0 && i - 1 >= hint.start && i - 1 < hint.end && !(i >= hint.start && i < hint.end) {
-                        crossterm::execute!(stdout(),crossterm::style::SetForegroundColor(Color::White))?;
-                    }
-                }
-                print!("{}",c);
-                i += 1;
-            }
+        // Builds the whole frame in memory and sends it to the terminal in a single write.
+        fn draw(&mut self) -> Result<(),Box<dyn Error>> {
+            let mut frame = Vec::new();
+            self.render(&mut frame)?;
 
-            print!("{}",crossterm::cursor::MoveTo(0,0));
-            stdout().flush().unwrap();
-            print!("{}",crossterm::cursor::Show);
-            stdout().flush().unwrap();
+            let mut out = stdout();
+            out.write_all(&frame)?;
+            out.flush()?;
 
             Ok(())
         }
@@ -129,16 +347,6 @@ pub mod backends {
         fn height(&self) -> usize {
             self.height
         }
-
-        fn to_string(&self) -> String {
-            let mut string = String::with_capacity(self.buffer.len());
-
-            for c in &self.buffer {
-                string.push(*c);
-            }
-
-            string
-        }
     }
 }
 
@@ -148,50 +356,165 @@ pub enum UpdateResult {
     Exit
 }
 
+impl<T> Default for TermWindow<T> where T: Drawable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> TermWindow<T> where T: Drawable {
     pub fn new() -> Self {
         let (w,h) = crossterm::terminal::size().unwrap();
         Self {
             editor: Editor::new(),
-            screen: T::new(w.into(),h.into())
+            alternate_screen: true,
+            screen: T::new(w.into(),h.into()),
+            terminated: Arc::new(AtomicBool::new(false))
         }
     }
 
     pub fn start(&mut self) -> Result<(),Box<dyn Error>> {
-        enable_raw_mode()?;
+        install_panic_hook(self.alternate_screen);
+        watch_signals(&self.terminated)?;
+        let mut guard = TerminalGuard::enter(self.alternate_screen)?;
 
-        if self.editor.open_docs.len() == 0 {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.run()));
+
+        if let Ok(Ok(())) = result {
+            return Ok(());
+        }
+
+        // Something went wrong: leave the screen as it is so the error stays readable,
+        // and get copies of unsaved work out before anything else can fail.
+        guard.clear_on_exit = false;
+        let recovered = self.editor.write_recovery_files();
+        drop(guard);
+        for copy in recovered {
+            match copy {
+                Ok(path) => eprintln!("vce: unsaved changes were written to {}",path),
+                Err(err) => eprintln!("vce: couldn't save a recovery copy of {}",err)
+            }
+        }
+
+        match result {
+            Ok(result) => result,
+            Err(panic) => panic::resume_unwind(panic)
+        }
+    }
+
+    fn run(&mut self) -> Result<(),Box<dyn Error>> {
+        if self.editor.open_docs.is_empty() {
             self.editor.make_new_doc("new 1".to_string());
         }
 
         self.editor.currently_open_doc = Some(0);
-        
-        let mut stdout = stdout();
-        execute!(stdout, EnableMouseCapture)?;
 
-        execute!(stdout,crossterm::terminal::Clear(crossterm::terminal::ClearType::All))?;
-        self.screen.clear(' ');
-        self.editor.draw(&mut self.screen);
-        self.screen.draw()?;
-        self.editor.update_cursor();
+        self.redraw()?;
         loop {
-            match self.editor.update(&mut self.screen, read().unwrap()) {
-                UpdateResult::Draw => {
-                    self.screen.clear(' ');
-                    self.editor.draw(&mut self.screen);
-                    self.screen.draw()?;
-                    self.editor.update_cursor();
-                },
+            // A signal can interrupt the poll itself, so check for one before looking at its result.
+            let ready = poll(Duration::from_millis(250));
+            if self.terminated.load(Ordering::SeqCst) {
+                return Err("terminated by a signal".into());
+            }
+
+            if !ready? {
+                let expired = self.editor.expire_message();
+                let reloaded = self.editor.reload_if_changed();
+                if expired || reloaded {
+                    self.redraw()?;
+                }
+                continue;
+            }
+
+            match self.editor.update(&mut self.screen, read()?) {
+                UpdateResult::Draw => self.redraw()?,
                 UpdateResult::NOp => {},
                 UpdateResult::Exit => break
             }
         }
 
-        execute!(stdout,crossterm::terminal::Clear(crossterm::terminal::ClearType::All))?;
-        execute!(stdout,crossterm::cursor::MoveTo(0,0))?;
-        execute!(stdout, DisableMouseCapture)?;
-        disable_raw_mode()?;
+        Ok(())
+    }
 
+    fn redraw(&mut self) -> Result<(),Box<dyn Error>> {
+        self.screen.set_color_mode(self.editor.color_mode);
+        self.screen.clear(' ');
+        self.editor.draw(&mut self.screen);
+        self.screen.draw()?;
+        self.editor.update_cursor(self.screen.height());
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// Holds the terminal in raw mode with mouse capture for as long as it lives, and puts it back when
+// dropped, including while unwinding or returning early with an error.
+struct TerminalGuard {
+    alternate_screen: bool,
+    clear_on_exit: bool
+}
+
+impl TerminalGuard {
+    fn enter(alternate_screen: bool) -> Result<Self,Box<dyn Error>> {
+        enable_raw_mode()?;
+        let guard = TerminalGuard {
+            alternate_screen,
+            clear_on_exit: true
+        };
+
+        if alternate_screen {
+            execute!(stdout(), EnterAlternateScreen)?;
+        }
+        execute!(stdout(), EnableMouseCapture)?;
+        execute!(stdout(),crossterm::terminal::Clear(crossterm::terminal::ClearType::All))?;
+
+        Ok(guard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        // Leaving the alternate screen brings back what was there before, so only the main screen needs clearing.
+        if self.clear_on_exit && !self.alternate_screen {
+            let _ = execute!(stdout(),crossterm::terminal::Clear(crossterm::terminal::ClearType::All),crossterm::cursor::MoveTo(0,0));
+        }
+        restore_terminal(self.alternate_screen);
+    }
+}
+
+// Safe to call more than once: the panic hook and the guard both run it on the way out.
+fn restore_terminal(alternate_screen: bool) {
+    let _ = execute!(stdout(),
+        DisableMouseCapture,
+        crossterm::style::ResetColor,
+        crossterm::cursor::Show);
+    if alternate_screen {
+        let _ = execute!(stdout(), LeaveAlternateScreen);
+    }
+    let _ = disable_raw_mode();
+}
+
+// Turns SIGHUP (the terminal going away) and SIGTERM into a flag the run loop checks, instead of the
+// default of dying on the spot with the terminal still in raw mode and unsaved work lost.
+#[cfg(unix)]
+fn watch_signals(terminated: &Arc<AtomicBool>) -> Result<(),Box<dyn Error>> {
+    for &signal in &[signal_hook::SIGHUP,signal_hook::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(terminated))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn watch_signals(_terminated: &Arc<AtomicBool>) -> Result<(),Box<dyn Error>> {
+    Ok(())
+}
+
+// Restores the terminal before the panic message is printed, so it isn't mangled by raw mode
+// or lost with the alternate screen.
+fn install_panic_hook(alternate_screen: bool) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore_terminal(alternate_screen);
+        println!();
+        default_hook(info);
+    }));
+}